@@ -7,10 +7,35 @@ pub enum EscrowError {
     TooLate = 2,
     NotExpired = 3,
     Unauthorized = 4,
-    NoSol = 5,
+    ZeroAmount = 5,
     InvalidMint = 6,
     TokenTransferFailed = 7,
     InvalidTokenAccount = 8,
+    TooManyBoxes = 9,
+    Paused = 10,
+    StakeTooSmall = 11,
+    StakeTooLarge = 12,
+    MintNotAllowed = 13,
+    FreezableMint = 14,
+    InvalidSignerSet = 15,
+    ConfigPending = 16,
+    NoConfigPending = 17,
+    TimelockActive = 18,
+    InsufficientTreasury = 19,
+    SweepNotInitiated = 20,
+    SweepAlreadyInitiated = 21,
+    DisputeWindowOpen = 22,
+    InvalidGuardianSet = 23,
+    TitleTooLong = 24,
+    InvalidReadingProof = 25,
+    MetaTooLong = 26,
+    BoxStillActive = 27,
+    SelfReferral = 28,
+    ReferrerLocked = 29,
+    RelayerFeeTooHigh = 30,
+    AccountNotMigrated = 31,
+    TimerRunning = 32,
+    TimerStopped = 33,
 }
 
 impl From<EscrowError> for ProgramError {
@@ -0,0 +1,444 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    entrypoint::ProgramResult,
+    log::sol_log_data,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use crate::state::{DeadlineKind, ProtocolConfig};
+
+/// SOL box created and funded
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BoxCreated {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub id: Pubkey,
+    pub content_hash: [u8; 32],
+    pub amount: u64,
+    pub deadline: i64,
+    pub deadline_kind: DeadlineKind,
+    pub timestamp: i64,
+}
+
+/// SOL box opened before its deadline, funds returned
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BoxOpened {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+    pub deadline: i64,
+    pub timestamp: i64,
+}
+
+/// Expired SOL box swept, funds forfeited (`amount` to the destination, `bounty` to the keeper)
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BoxSwept {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub destination: Pubkey,
+    pub keeper: Pubkey,
+    pub amount: u64,
+    pub bounty: u64,
+    pub deadline: i64,
+    pub timestamp: i64,
+}
+
+/// Sweep of an expired box (SOL or token) started; it can be appealed until `finalize_after`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct SweepInitiated {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub finalize_after: i64,
+    pub timestamp: i64,
+}
+
+/// Key of a box (SOL or token) replaced by its sender
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BoxIdRotated {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub old_id: Pubkey,
+    pub new_id: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Box id key committed to a reading proof of a box (SOL or token) before its deadline
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct FinishCommitted {
+    pub box_pda: Pubkey,
+    pub proof_hash: [u8; 32],
+    pub committed_at: i64, // in the units of the box's deadline kind
+    pub timestamp: i64,
+}
+
+/// Reading timer of a `ReadingMode::Timer` box (SOL or token) started or stopped
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct TimerSet {
+    pub box_pda: Pubkey,
+    pub running: bool,
+    pub elapsed: i64, // seconds counted by the runs stopped so far
+    pub timestamp: i64,
+}
+
+/// Guardians approved a recovery of a box (SOL or token), effective from `effective_at`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct RecoveryQueued {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub new_id: Pubkey,
+    pub payout: Pubkey,
+    pub effective_at: i64,
+    pub timestamp: i64,
+}
+
+/// Labels of a box (SOL or token) set or changed
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BoxMetaSet {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Labels of a settled box (SOL or token) removed, rent returned to the sender
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BoxMetaClosed {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Non-transferable badge minted to the reader of an opened box (SOL or token)
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BadgeMinted {
+    pub box_pda: Pubkey,
+    pub owner: Pubkey,
+    pub badge_mint: Pubkey,
+    pub content_hash: [u8; 32],
+    pub timestamp: i64,
+}
+
+/// Referrer recorded on a sender's first box
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ReferrerSet {
+    pub sender: Pubkey,
+    pub referrer: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Referrer's share of a referred sender's forfeit to the treasury
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ReferralAccrued {
+    pub referrer: Pubkey,
+    pub sender: Pubkey,
+    pub mint: Option<Pubkey>, // None = lamports accrued in the Referral PDA; else paid to the referrer's ATA
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Referrer's share of a forfeit left with the treasury because the referrer had not opened
+/// their Referral PDA (SOL) or their ATA of the box's mint (tokens) yet
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ReferralSkipped {
+    pub referrer: Pubkey,
+    pub sender: Pubkey,
+    pub mint: Option<Pubkey>, // None = lamports
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Accrued referral rewards paid out to the referrer
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ReferralRewardsClaimed {
+    pub referrer: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Relayer paid from the payout of a box (SOL or token) it opened for the reader
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct RelayerPaid {
+    pub box_pda: Pubkey,
+    pub relayer: Pubkey, // relayer token account for token boxes
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Token box created and its vault funded
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct TokenBoxCreated {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub id: Pubkey,
+    pub content_hash: [u8; 32],
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub deadline: i64,
+    pub deadline_kind: DeadlineKind,
+    pub timestamp: i64,
+}
+
+/// Token box opened before its deadline, tokens returned
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct TokenBoxOpened {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub recipient: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub deadline: i64,
+    pub timestamp: i64,
+}
+
+/// Expired token box swept, tokens forfeited (`amount` to the destination, `bounty` to the keeper)
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct TokenBoxSwept {
+    pub box_pda: Pubkey,
+    pub sender: Pubkey,
+    pub destination: Pubkey,
+    pub keeper: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub bounty: u64,
+    pub deadline: i64,
+    pub timestamp: i64,
+}
+
+/// Program authority set or replaced (`old_authority` is the default key on initialize)
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct AuthorityChanged {
+    pub program_state: Pubkey,
+    pub old_authority: Pubkey,
+    pub new_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Authority changed which operations are paused
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct PauseChanged {
+    pub authority: Pubkey,
+    pub create: bool,
+    pub open: bool,
+    pub sweep: bool,
+    pub timestamp: i64,
+}
+
+/// Mint added to the token box allowlist or its settings updated
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct MintConfigured {
+    pub mint: Pubkey,
+    pub decimals: u8,
+    pub min_stake: u64,
+    pub max_stake: u64,
+    pub allow_freeze_authority: bool,
+    pub timestamp: i64,
+}
+
+/// Mint removed from the token box allowlist
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct MintRemoved {
+    pub mint: Pubkey,
+    pub timestamp: i64,
+}
+
+/// SOL withdrawn from the treasury PDA
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct TreasuryWithdrawn {
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Tokens withdrawn from a treasury token account
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct TreasuryTokenWithdrawn {
+    pub destination: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Account rewritten in the current layout by `MigrateAccount`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct AccountMigrated {
+    pub account: Pubkey,
+    pub version: u8,
+    pub timestamp: i64,
+}
+
+/// Config change queued behind the timelock
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ConfigQueued {
+    pub config: ProtocolConfig,
+    pub execute_after: i64,
+    pub timestamp: i64,
+}
+
+/// Queued config change applied
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ConfigExecuted {
+    pub config: ProtocolConfig,
+    pub timestamp: i64,
+}
+
+/// Queued config change dropped before it was applied
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ConfigCancelled {
+    pub config: ProtocolConfig,
+    pub timestamp: i64,
+}
+
+/// Every event emitted by the program. Events are Borsh encoded (one byte
+/// variant tag followed by the event struct) and written with `sol_log_data`,
+/// so they show up as `Program data: <base64>` lines in transaction logs.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum EscrowEvent {
+    BoxCreated(BoxCreated),
+    BoxOpened(BoxOpened),
+    BoxSwept(BoxSwept),
+    TokenBoxCreated(TokenBoxCreated),
+    TokenBoxOpened(TokenBoxOpened),
+    TokenBoxSwept(TokenBoxSwept),
+    AuthorityChanged(AuthorityChanged),
+    PauseChanged(PauseChanged),
+    MintConfigured(MintConfigured),
+    MintRemoved(MintRemoved),
+    ConfigQueued(ConfigQueued),
+    ConfigExecuted(ConfigExecuted),
+    ConfigCancelled(ConfigCancelled),
+    TreasuryWithdrawn(TreasuryWithdrawn),
+    TreasuryTokenWithdrawn(TreasuryTokenWithdrawn),
+    SweepInitiated(SweepInitiated),
+    BoxIdRotated(BoxIdRotated),
+    RecoveryQueued(RecoveryQueued),
+    BoxMetaSet(BoxMetaSet),
+    BoxMetaClosed(BoxMetaClosed),
+    BadgeMinted(BadgeMinted),
+    ReferrerSet(ReferrerSet),
+    ReferralAccrued(ReferralAccrued),
+    ReferralRewardsClaimed(ReferralRewardsClaimed),
+    RelayerPaid(RelayerPaid),
+    AccountMigrated(AccountMigrated),
+    FinishCommitted(FinishCommitted),
+    TimerSet(TimerSet),
+    ReferralSkipped(ReferralSkipped),
+}
+
+impl EscrowEvent {
+    /// Prefix the runtime puts in front of `sol_log_data` output
+    pub const LOG_PREFIX: &'static str = "Program data: ";
+
+    /// Write the event to the transaction log
+    pub fn emit(&self) -> ProgramResult {
+        let data = borsh::to_vec(self)?;
+        sol_log_data(&[&data]);
+        Ok(())
+    }
+
+    /// Decode an event from the raw bytes of a `sol_log_data` entry
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        Self::try_from_slice(data).map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    /// Decode an event from a `Program data: <base64>` log line.
+    /// Returns `None` for any other log line or for data that is not an `EscrowEvent`.
+    #[cfg(not(target_os = "solana"))]
+    pub fn from_log(line: &str) -> Option<Self> {
+        use base64::Engine;
+
+        let encoded = line.strip_prefix(Self::LOG_PREFIX)?;
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .ok()?;
+        Self::unpack(&data).ok()
+    }
+
+    /// Decode every event of this program found in a transaction's log messages
+    #[cfg(not(target_os = "solana"))]
+    pub fn from_logs<'a, I>(lines: I) -> Vec<Self>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        lines.into_iter().filter_map(Self::from_log).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_stubs;
+    use base64::Engine;
+    use solana_program::clock::Clock;
+
+    fn opened() -> EscrowEvent {
+        EscrowEvent::BoxOpened(BoxOpened {
+            box_pda: Pubkey::new_unique(),
+            sender: Pubkey::new_unique(),
+            recipient: Pubkey::new_unique(),
+            amount: 1_000_000,
+            deadline: 1_700_000_000,
+            timestamp: 1_699_000_000,
+        })
+    }
+
+    fn log_line(data: &[u8]) -> String {
+        format!("{}{}", EscrowEvent::LOG_PREFIX, base64::engine::general_purpose::STANDARD.encode(data))
+    }
+
+    #[test]
+    fn emitted_event_decodes() {
+        test_stubs::install(Clock::default());
+        test_stubs::take_logged();
+
+        let event = opened();
+        event.emit().unwrap();
+
+        let logged = test_stubs::take_logged();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(EscrowEvent::unpack(&logged[0]).unwrap(), event);
+        assert_eq!(EscrowEvent::from_log(&log_line(&logged[0])), Some(event));
+    }
+
+    #[test]
+    fn from_log_parses_program_data_lines() {
+        let event = EscrowEvent::RelayerPaid(RelayerPaid {
+            box_pda: Pubkey::new_unique(),
+            relayer: Pubkey::new_unique(),
+            amount: 5_000,
+            timestamp: 1_699_000_000,
+        });
+        let line = log_line(&borsh::to_vec(&event).unwrap());
+
+        assert_eq!(EscrowEvent::from_log(&line), Some(event.clone()));
+        assert_eq!(EscrowEvent::from_log(&format!("{line}\n")), Some(event));
+        assert_eq!(EscrowEvent::from_log("Program log: Instruction: OpenBox"), None);
+        assert_eq!(EscrowEvent::from_log("Program data: not base64!"), None);
+        assert_eq!(EscrowEvent::from_log(&log_line(&[0xff, 1, 2])), None);
+    }
+
+    #[test]
+    fn from_logs_keeps_only_events() {
+        let first = opened();
+        let second = opened();
+        let lines = [
+            "Program 11111111111111111111111111111111 invoke [1]".to_string(),
+            log_line(&borsh::to_vec(&first).unwrap()),
+            "Program log: unrelated".to_string(),
+            log_line(&borsh::to_vec(&second).unwrap()),
+            "Program 11111111111111111111111111111111 success".to_string(),
+        ];
+
+        let events = EscrowEvent::from_logs(lines.iter().map(String::as_str));
+        assert_eq!(events, vec![first, second]);
+    }
+
+    #[test]
+    fn unpack_rejects_truncated_data() {
+        let data = borsh::to_vec(&opened()).unwrap();
+        assert_eq!(
+            EscrowEvent::unpack(&data[..data.len() - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+}
@@ -1,16 +1,25 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
+    hash::hashv,
+    instruction::{AccountMeta, Instruction},
     program_error::ProgramError,
     pubkey::Pubkey,
+    system_program,
 };
+use spl_associated_token_account::{get_associated_token_address, get_associated_token_address_with_program_id};
 
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
+use crate::state::{BoxMetaFields, DeadlineKind, ProtocolConfig, ReadingMode};
+
+/// Instructions are encoded by `pack`/`unpack`: a one-byte tag followed by a Borsh payload.
+/// A tag is never reassigned once used, so the tag order does not follow the variant order.
+#[derive(Debug, PartialEq)]
 pub enum EscrowInstruction {
-    /// Initialize program state with authority
+    /// Initialize program state with authority. Forfeits of new boxes go to the treasury PDA.
     /// Accounts:
     /// 0. `[writable, signer]` Authority account
     /// 1. `[writable]` Program state PDA
     /// 2. `[]` System program
+    /// 3. `[writable]` Treasury PDA (seeds: ["treasury"])
     Initialize,
 
     /// Create a new escrow box
@@ -18,24 +27,63 @@ pub enum EscrowInstruction {
     /// 0. `[writable, signer]` Sender account
     /// 1. `[writable]` Box PDA account
     /// 2. `[]` System program
+    /// 3. `[writable]` UserProfile PDA (seeds: ["profile", sender]), created on first use
+    /// 4. `[writable]` Program state PDA
+    /// 5. `[writable]` BoxMeta PDA (seeds: ["box_meta", box_pda]), only when `options.meta` is set
     CreateBox {
         id: Pubkey,
         deadline_days: u16,
         amount: u64,
+        options: BoxOptions,
     },
 
-    /// Open an escrow box (before deadline)
+    /// Open an escrow box (before deadline), authorized by the box id key alone so that anyone,
+    /// e.g. a relayer, can pay the transaction fee. `relayer_fee` (at most the box's relayer fee
+    /// cap) is deducted from the payout and paid to the relayer account.
+    /// `proof` must match the box's reading mode. During the grace period after the deadline
+    /// the box can only be opened with the proof committed to by a `CommitFinish` made before
+    /// the deadline.
+    /// With `mint_badge`, a non-transferable Token-2022 badge referencing the book hash and
+    /// completion date is minted to the sender (or the recovery payout). Minting takes about
+    /// ten CPIs, including the owner's ATA creation, so its cost has not been pinned to a
+    /// number: clients should simulate the transaction and set a compute unit limit from the
+    /// result (`ComputeBudgetInstruction::set_compute_unit_limit`) rather than rely on the
+    /// default limit.
+    /// An empty payload means no proof, no badge and no relayer fee.
     /// Accounts:
     /// 0. `[writable]` Box PDA account
-    /// 1. `[writable]` Recipient account
-    OpenBox,
+    /// 1. `[writable]` Recipient account (the recovery payout once a guardian recovery took effect)
+    /// 2. `[writable]` UserProfile PDA of the box sender
+    /// 3. `[writable]` Program state PDA
+    /// 4. `[signer]` Box id key
+    /// 5. `[writable, signer]` Badge payer, only with `mint_badge` (pays the badge accounts' rent)
+    /// 6. `[writable]` Badge mint PDA (seeds: ["badge", box_pda])
+    /// 7. `[]` Badge authority PDA (seeds: ["badge_authority"])
+    /// 8. `[writable]` Badge owner's Token-2022 ATA of the badge mint
+    /// 9. `[]` Badge owner account
+    /// 10. `[]` Token-2022 program
+    /// 11. `[]` Associated token program
+    /// 12. `[]` System program
+    /// 13. `[writable]` Relayer account, only with a nonzero `relayer_fee` (5. without a badge)
+    OpenBox {
+        proof: ReadingProof,
+        mint_badge: bool,
+        relayer_fee: u64,
+    },
 
-    /// Sweep expired box (after deadline, funds go to authority)
+    /// Finish the sweep of an expired box once the dispute window after `InitiateSweep` has
+    /// passed without an appeal (funds go to the beneficiary the box was created under).
+    /// Anyone may call; the keeper bounty of the box's terms goes to the keeper.
+    /// When the beneficiary is the treasury, `referral_share_bps` of the rest goes to the
+    /// sender's referrer, once they have opened their Referral PDA.
     /// Accounts:
-    /// 0. `[]` Program state PDA
+    /// 0. `[writable]` Program state PDA
     /// 1. `[writable]` Box PDA account
-    /// 2. `[writable]` Authority account
-    SweepBox,
+    /// 2. `[writable]` Beneficiary account
+    /// 3. `[writable]` UserProfile PDA of the box sender
+    /// 4. `[writable]` Keeper account
+    /// 5. `[writable]` Referral PDA (seeds: ["referral", referrer]), only when the sender has a referrer
+    FinalizeSweep,
 
     /// Create a new token escrow box
     /// Accounts:
@@ -48,65 +96,851 @@ pub enum EscrowInstruction {
     /// 6. `[]` Token program
     /// 7. `[]` Associated token program
     /// 8. `[]` System program
+    /// 9. `[writable]` UserProfile PDA (seeds: ["profile", sender]), created on first use
+    /// 10. `[writable]` Program state PDA
+    /// 11. `[]` MintConfig PDA (seeds: ["mint_config", mint])
+    /// 12. `[writable]` BoxMeta PDA (seeds: ["box_meta", token_box_pda]), only when `options.meta` is set
     CreateBoxToken {
         id: Pubkey,
         deadline_days: u16,
         amount: u64,
+        options: BoxOptions,
     },
 
-    /// Open a token escrow box (before deadline)
+    /// Open a token escrow box (before deadline, or during the grace period as for `OpenBox`).
+    /// As for `OpenBox` the id key authorizes it and `relayer_fee` is paid in the box's tokens.
     /// Accounts:
     /// 0. `[writable]` TokenBox PDA account
     /// 1. `[writable]` Vault ATA
-    /// 2. `[writable]` Recipient token account
+    /// 2. `[writable]` Recipient token account (ATA of the recovery payout once a guardian recovery took effect)
     /// 3. `[writable]` Sender account (for rent return)
     /// 4. `[]` Vault authority PDA (seeds: ["vault", token_box_pda])
     /// 5. `[]` Token program
-    OpenBoxToken,
+    /// 6. `[writable]` UserProfile PDA of the box sender
+    /// 7. `[writable]` Program state PDA
+    /// 8. `[signer]` Box id key
+    /// 9. `[writable, signer]` Badge payer, only with `mint_badge` (pays the badge accounts' rent)
+    /// 10. `[writable]` Badge mint PDA (seeds: ["badge", token_box_pda])
+    /// 11. `[]` Badge authority PDA (seeds: ["badge_authority"])
+    /// 12. `[writable]` Badge owner's Token-2022 ATA of the badge mint
+    /// 13. `[]` Badge owner account
+    /// 14. `[]` Token-2022 program
+    /// 15. `[]` Associated token program
+    /// 16. `[]` System program
+    /// 17. `[writable]` Relayer token account (same mint), only with a nonzero `relayer_fee`
+    ///     (9. without a badge)
+    OpenBoxToken {
+        proof: ReadingProof,
+        mint_badge: bool,
+        relayer_fee: u64,
+    },
 
-    /// Sweep expired token box (after deadline, tokens go to authority)
+    /// Token variant of `FinalizeSweep` (tokens go to the beneficiary the box was created under).
+    /// Anyone may call; the keeper bounty of the box's terms goes to the keeper token account.
+    /// The beneficiary's ATA must already exist; for the treasury PDA anyone can create it
+    /// through the associated token program. The referrer's share is paid to their ATA of the
+    /// mint, and stays with the treasury while that ATA does not exist.
     /// Accounts:
-    /// 0. `[]` Program state PDA
+    /// 0. `[writable]` Program state PDA
     /// 1. `[writable]` TokenBox PDA account
     /// 2. `[writable]` Vault ATA
-    /// 3. `[writable]` Authority token account
-    /// 4. `[signer]` Authority
+    /// 3. `[writable]` Beneficiary token account (ATA of the box's beneficiary)
+    /// 4. `[writable]` Sender account (for rent return)
     /// 5. `[]` Vault authority PDA (seeds: ["vault", token_box_pda])
     /// 6. `[]` Token program
-    SweepBoxToken,
+    /// 7. `[writable]` UserProfile PDA of the box sender
+    /// 8. `[writable]` Keeper token account (same mint)
+    /// 9. `[writable]` Referrer token account (ATA of the sender's referrer), only when the sender
+    ///    has a referrer
+    FinalizeSweepToken,
+
+    /// Create several escrow boxes in one instruction (at most `MAX_BATCH_BOXES`).
+    /// One Box PDA account follows the fixed accounts for each entry, in entry order,
+    /// followed by the entry's BoxMeta PDA when its `options.meta` is set.
+    /// Accounts:
+    /// 0. `[writable, signer]` Sender account
+    /// 1. `[]` System program
+    /// 2. `[writable]` UserProfile PDA (seeds: ["profile", sender]), created on first use
+    /// 3. `[writable]` Program state PDA
+    /// 4. `[writable]` Box PDA account of the first entry
+    CreateBoxes {
+        entries: Vec<BoxEntry>,
+    },
+
+    /// Create several token escrow boxes of the same mint in one instruction
+    /// (at most `MAX_BATCH_BOXES`). The sender, mint and programs are validated once;
+    /// a (TokenBox, Vault ATA, Vault authority) triple follows for each entry, in entry order,
+    /// followed by the entry's BoxMeta PDA when its `options.meta` is set.
+    /// Accounts:
+    /// 0. `[writable, signer]` Sender account
+    /// 1. `[writable]` Sender token account (ATA)
+    /// 2. `[]` Mint account
+    /// 3. `[]` Token program
+    /// 4. `[]` Associated token program
+    /// 5. `[]` System program
+    /// 6. `[writable]` UserProfile PDA (seeds: ["profile", sender]), created on first use
+    /// 7. `[writable]` Program state PDA
+    /// 8. `[]` MintConfig PDA (seeds: ["mint_config", mint])
+    /// 9. `[writable]` TokenBox PDA account of the first entry
+    /// 10. `[writable]` Vault ATA (PDA-owned) of the first entry
+    /// 11. `[]` Vault authority PDA (seeds: ["vault", token_box_pda]) of the first entry
+    CreateBoxesToken {
+        entries: Vec<BoxEntry>,
+    },
+
+    /// Pause or resume box creation, opening and sweeping (authority only).
+    /// While opening is paused every active box's deadline is pushed out by the paused time.
+    /// Requires authority approval, see `QueueConfig`, but takes effect at once: it is an
+    /// emergency stop, and no pause can move a stake or shorten a deadline. A pause can hold
+    /// stakes in their boxes for as long as it lasts; that is the price of the stop.
+    /// Accounts:
+    /// 0. `[signer]` Authority
+    /// 1. `[writable]` Program state PDA
+    SetPaused {
+        create: bool,
+        open: bool,
+        sweep: bool,
+    },
+
+    /// Allow token boxes of a mint, or update its settings (authority only).
+    /// Stakes are in raw token units, `max_stake` 0 = no limit. Mints with a freeze
+    /// authority are rejected unless `allow_freeze_authority` is set.
+    /// Requires authority approval, see `QueueConfig`; the authority also pays the rent.
+    /// Not timelocked: the settings are only read when a box is created, so existing boxes
+    /// of the mint are unaffected.
+    /// Accounts:
+    /// 0. `[writable, signer]` Authority
+    /// 1. `[]` Program state PDA
+    /// 2. `[writable]` MintConfig PDA (seeds: ["mint_config", mint]), created on first use
+    /// 3. `[]` Mint account
+    /// 4. `[]` System program
+    AddMint {
+        min_stake: u64,
+        max_stake: u64,
+        allow_freeze_authority: bool,
+    },
+
+    /// Remove a mint from the allowlist, returning the MintConfig rent to the authority.
+    /// Existing boxes of the mint can still be opened and swept.
+    /// Requires authority approval, see `QueueConfig`. Not timelocked, for the same reason
+    /// as `AddMint`.
+    /// Accounts:
+    /// 0. `[writable, signer]` Authority
+    /// 1. `[]` Program state PDA
+    /// 2. `[writable]` MintConfig PDA (seeds: ["mint_config", mint])
+    RemoveMint,
+
+    /// Queue a replacement of the protocol config (authority, SOL stake limits, signer set
+    /// and the terms new boxes are created under). It can be executed once `CONFIG_TIMELOCK`
+    /// has passed; boxes keep the terms they were created with.
+    /// With a non-zero signer `threshold`, instructions that require authority approval no
+    /// longer need the authority key's signature; instead at least `threshold` distinct keys
+    /// of the set must sign the same transaction and are passed as trailing accounts after
+    /// the instruction's regular accounts.
+    /// Requires authority approval under the current config; only one change may be queued.
+    /// Accounts:
+    /// 0. `[signer]` Authority (signature not required once a signer set is configured)
+    /// 1. `[writable, signer]` Payer of the PendingConfig rent
+    /// 2. `[]` Program state PDA
+    /// 3. `[writable]` PendingConfig PDA (seeds: ["pending_config"])
+    /// 4. `[]` System program
+    /// 5. `[signer]` Signer set members, when a signer set is configured
+    QueueConfig {
+        config: Box<ProtocolConfig>,
+    },
+
+    /// Apply the queued config once its timelock has passed (anyone may call).
+    /// The PendingConfig rent is returned to the account that queued it.
+    /// Accounts:
+    /// 0. `[writable]` Program state PDA
+    /// 1. `[writable]` PendingConfig PDA (seeds: ["pending_config"])
+    /// 2. `[writable]` Payer of the PendingConfig rent
+    ExecuteConfig,
+
+    /// Drop the queued config, returning the PendingConfig rent to the account that queued it.
+    /// Requires authority approval, see `QueueConfig`.
+    /// Accounts:
+    /// 0. `[signer]` Authority
+    /// 1. `[]` Program state PDA
+    /// 2. `[writable]` PendingConfig PDA (seeds: ["pending_config"])
+    /// 3. `[writable]` Payer of the PendingConfig rent
+    CancelConfig,
+
+    /// Withdraw SOL from the treasury PDA, which always keeps its rent-exempt minimum.
+    /// Requires authority approval, see `QueueConfig`.
+    /// Accounts:
+    /// 0. `[signer]` Authority
+    /// 1. `[]` Program state PDA
+    /// 2. `[writable]` Treasury PDA (seeds: ["treasury"])
+    /// 3. `[writable]` Destination account
+    WithdrawTreasury {
+        amount: u64,
+    },
+
+    /// Withdraw tokens from a treasury token account (an account owned by the treasury PDA).
+    /// Requires authority approval, see `QueueConfig`.
+    /// Accounts:
+    /// 0. `[signer]` Authority
+    /// 1. `[]` Program state PDA
+    /// 2. `[]` Treasury PDA (seeds: ["treasury"])
+    /// 3. `[writable]` Treasury token account
+    /// 4. `[writable]` Destination token account
+    /// 5. `[]` Token program
+    WithdrawTreasuryToken {
+        amount: u64,
+    },
+
+    /// Arbiter judges the reader finished: return the stake to the sender (before deadline)
+    /// Accounts:
+    /// 0. `[writable]` Box PDA account
+    /// 1. `[signer]` Arbiter named at creation
+    /// 2. `[writable]` Sender account (the recovery payout once a guardian recovery took effect)
+    /// 3. `[writable]` UserProfile PDA of the box sender
+    /// 4. `[writable]` Program state PDA
+    ArbiterRelease,
+
+    /// Arbiter judges the reader did not finish: forfeit the stake to the beneficiary the box
+    /// was created under (before deadline, no keeper bounty). The referrer's share is taken as
+    /// in `FinalizeSweep`.
+    /// Accounts:
+    /// 0. `[writable]` Box PDA account
+    /// 1. `[signer]` Arbiter named at creation
+    /// 2. `[writable]` Beneficiary account
+    /// 3. `[writable]` UserProfile PDA of the box sender
+    /// 4. `[writable]` Program state PDA
+    /// 5. `[writable]` Referral PDA (seeds: ["referral", referrer]), only when the sender has a referrer
+    ArbiterForfeit,
+
+    /// Token variant of `ArbiterRelease`
+    /// Accounts:
+    /// 0. `[writable]` TokenBox PDA account
+    /// 1. `[signer]` Arbiter named at creation
+    /// 2. `[writable]` Vault ATA
+    /// 3. `[writable]` Sender token account (ATA of the box sender, or of the recovery payout)
+    /// 4. `[writable]` Sender account (for rent return)
+    /// 5. `[]` Vault authority PDA (seeds: ["vault", token_box_pda])
+    /// 6. `[]` Token program
+    /// 7. `[writable]` UserProfile PDA of the box sender
+    /// 8. `[writable]` Program state PDA
+    ArbiterReleaseToken,
+
+    /// Token variant of `ArbiterForfeit`
+    /// Accounts:
+    /// 0. `[writable]` TokenBox PDA account
+    /// 1. `[signer]` Arbiter named at creation
+    /// 2. `[writable]` Vault ATA
+    /// 3. `[writable]` Beneficiary token account (ATA of the box's beneficiary)
+    /// 4. `[writable]` Sender account (for rent return)
+    /// 5. `[]` Vault authority PDA (seeds: ["vault", token_box_pda])
+    /// 6. `[]` Token program
+    /// 7. `[writable]` UserProfile PDA of the box sender
+    /// 8. `[writable]` Program state PDA
+    /// 9. `[writable]` Referrer token account (ATA of the sender's referrer), only when the sender
+    ///    has a referrer
+    ArbiterForfeitToken,
+
+    /// Mark an expired box (after deadline and grace period) as pending forfeit, starting the
+    /// dispute window of its terms. Anyone may call.
+    /// Accounts:
+    /// 0. `[]` Program state PDA
+    /// 1. `[writable]` Box PDA account
+    InitiateSweep,
+
+    /// Token variant of `InitiateSweep`
+    /// Accounts:
+    /// 0. `[]` Program state PDA
+    /// 1. `[writable]` TokenBox PDA account
+    InitiateSweepToken,
+
+    /// Appeal a pending sweep during its dispute window; a valid appeal returns the stake
+    /// as if the box had been opened: to the sender, or to the recovery payout once a guardian
+    /// recovery took effect. Appeals are accepted while paused.
+    /// Accounts:
+    /// 0. `[writable]` Box PDA account
+    /// 1. `[signer]` Sender account
+    /// 2. `[writable]` Recipient account (the sender, or the recovery payout)
+    /// 3. `[writable]` UserProfile PDA of the box sender
+    /// 4. `[writable]` Program state PDA
+    /// 5. Evidence accounts, see `AppealEvidence`
+    Appeal {
+        evidence: AppealEvidence,
+    },
+
+    /// Token variant of `Appeal`
+    /// Accounts:
+    /// 0. `[writable]` TokenBox PDA account
+    /// 1. `[writable]` Vault ATA
+    /// 2. `[writable]` Recipient token account (ATA of the sender, or of the recovery payout)
+    /// 3. `[writable, signer]` Sender account (for rent return)
+    /// 4. `[]` Vault authority PDA (seeds: ["vault", token_box_pda])
+    /// 5. `[]` Token program
+    /// 6. `[writable]` UserProfile PDA of the box sender
+    /// 7. `[writable]` Program state PDA
+    /// 8. Evidence accounts, see `AppealEvidence`
+    AppealToken {
+        evidence: AppealEvidence,
+    },
+
+    /// Replace the key of a box, e.g. after losing the phone that held it (before deadline).
+    /// The box keeps its address; `new_id` signs in place of the original id from then on.
+    /// Also cancels any guardian recovery of the box.
+    /// Accounts:
+    /// 0. `[writable]` Box PDA account
+    /// 1. `[signer]` Sender account
+    /// 2. `[]` Program state PDA
+    RotateBoxId {
+        new_id: Pubkey,
+    },
+
+    /// Token variant of `RotateBoxId`
+    /// Accounts:
+    /// 0. `[writable]` TokenBox PDA account
+    /// 1. `[signer]` Sender account
+    /// 2. `[]` Program state PDA
+    RotateBoxIdToken {
+        new_id: Pubkey,
+    },
+
+    /// Recover a box whose sender wallet is lost: after `RECOVERY_DELAY`, `new_id` becomes the
+    /// box key and payouts go to `payout`. Deadlines are unchanged. Requires the box's guardian
+    /// threshold; a new recovery replaces a queued one and the sender can cancel it with `RotateBoxId`.
+    /// Accounts:
+    /// 0. `[writable]` Box PDA account
+    /// 1. `[signer]` Guardians approving the recovery
+    RecoverBox {
+        new_id: Pubkey,
+        payout: Pubkey,
+    },
+
+    /// Token variant of `RecoverBox`
+    /// Accounts:
+    /// 0. `[writable]` TokenBox PDA account
+    /// 1. `[signer]` Guardians approving the recovery
+    RecoverBoxToken {
+        new_id: Pubkey,
+        payout: Pubkey,
+    },
+
+    /// Set the labels of a SOL or token box, creating its BoxMeta PDA if needed.
+    /// The account is resized to the new labels; the sender pays or gets back the rent difference.
+    /// Accounts:
+    /// 0. `[writable, signer]` Sender account
+    /// 1. `[]` Box or TokenBox PDA account
+    /// 2. `[writable]` BoxMeta PDA (seeds: ["box_meta", box_pda])
+    /// 3. `[]` System program
+    SetBoxMeta {
+        fields: BoxMetaFields,
+    },
+
+    /// Close the BoxMeta PDA of an opened or swept box and refund its rent to the sender
+    /// Accounts:
+    /// 0. `[writable, signer]` Sender account
+    /// 1. `[]` Box or TokenBox PDA account
+    /// 2. `[writable]` BoxMeta PDA (seeds: ["box_meta", box_pda])
+    CloseBoxMeta,
+
+    /// Pay out the SOL referral rewards accrued so far. The first call opens the referrer's
+    /// Referral PDA instead; shares of forfeits only accrue once it exists.
+    /// Accounts:
+    /// 0. `[writable, signer]` Referrer account
+    /// 1. `[writable]` Referral PDA (seeds: ["referral", referrer])
+    /// 2. `[]` System program
+    ClaimReferralRewards,
+
+    /// Rewrite an account created before accounts carried kind and version bytes in the
+    /// current layout; those accounts are rejected with `AccountNotMigrated` until then.
+    /// The program state must be migrated before any box. A migrated box keeps its sender,
+    /// id, deadline and stake and is settled under `BoxTerms::legacy`, whatever the config.
+    /// Accounts:
+    /// 0. `[writable, signer]` Payer of the extra rent
+    /// 1. `[writable]` Account to migrate: the program state PDA, a Box or a TokenBox PDA
+    /// 2. `[]` System program
+    /// 3. `[writable]` Treasury PDA, created if missing, when migrating the program state;
+    ///    the program state PDA when migrating a box
+    /// 4. `[writable]` Box sender's profile PDA (seeds: ["profile", sender]), created if missing;
+    ///    boxes only
+    MigrateAccount,
+
+    /// Record, while the deadline has not passed, the `ReadingProof::hash` of the proof the box
+    /// will be opened with, so that it can still be opened with that proof during the grace
+    /// period. Signed by the box id key; a later commit replaces an earlier one.
+    /// Accounts:
+    /// 0. `[writable]` Box PDA account
+    /// 1. `[signer]` Box id key
+    /// 2. `[]` Program state PDA
+    CommitFinish {
+        proof_hash: [u8; 32],
+    },
+
+    /// Token variant of `CommitFinish`
+    /// Accounts:
+    /// 0. `[writable]` TokenBox PDA account
+    /// 1. `[signer]` Box id key
+    /// 2. `[]` Program state PDA
+    CommitFinishToken {
+        proof_hash: [u8; 32],
+    },
+
+    /// Start (`running`) or stop the reading timer of a `ReadingMode::Timer` box. Signed by the
+    /// box id key and only while the deadline has not passed, so a timer still running at the
+    /// deadline counts nothing from that run.
+    /// Accounts:
+    /// 0. `[writable]` Box PDA account
+    /// 1. `[signer]` Box id key
+    /// 2. `[]` Program state PDA
+    SetTimer {
+        running: bool,
+    },
+
+    /// Token variant of `SetTimer`
+    /// Accounts:
+    /// 0. `[writable]` TokenBox PDA account
+    /// 1. `[signer]` Box id key
+    /// 2. `[]` Program state PDA
+    SetTimerToken {
+        running: bool,
+    },
+}
+
+/// Maximum number of boxes a single `CreateBoxes`/`CreateBoxesToken` may create
+pub const MAX_BATCH_BOXES: usize = 8;
+
+/// Proof offered with an `Appeal` that the reader finished in time
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum AppealEvidence {
+    /// The attestor named in the box's terms co-signs the appeal.
+    /// Evidence accounts: 0. `[signer]` Attestor
+    Attestation,
+    /// The checkpoint codes of a `ReadingMode::Checkpoints` box. No evidence accounts.
+    CheckpointReveal { codes: Vec<[u8; 32]> },
+}
+
+/// Reading evidence submitted with `OpenBox`/`OpenBoxToken`, checked against the box's `ReadingMode`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default, PartialEq)]
+pub enum ReadingProof {
+    /// Enough for `ReadingMode::Unspecified` boxes, and for `ReadingMode::Timer` boxes whose
+    /// timer counted the target
+    #[default]
+    Empty,
+    /// Checkpoint codes in book order
+    Checkpoints { codes: Vec<[u8; 32]> },
+}
+
+impl ReadingProof {
+    /// SHA-256 of the Borsh-encoded proof, as committed to by `CommitFinish`
+    pub fn hash(&self) -> [u8; 32] {
+        hashv(&[&borsh::to_vec(self).unwrap()]).to_bytes()
+    }
+}
+
+/// Optional settings of a new box. `CreateBox`/`CreateBoxToken` may omit them entirely.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default, PartialEq)]
+pub struct BoxOptions {
+    /// Accountability partner who may release or forfeit the box before its deadline
+    pub arbiter: Option<Pubkey>,
+    /// Keys that can recover the box if the sender wallet is lost (at most `MAX_GUARDIANS`)
+    pub guardians: Vec<Pubkey>,
+    /// Guardian signatures `RecoverBox` needs; 0 when there are no guardians
+    pub guardian_threshold: u8,
+    /// SHA-256 of the book's EPUB/PDF file
+    pub content_hash: Option<[u8; 32]>,
+    /// Short book title, at most `MAX_TITLE_LEN` bytes
+    pub title: Option<String>,
+    /// How reading is verified when the box is opened
+    pub reading_mode: ReadingMode,
+    /// Labels stored in the box's BoxMeta PDA
+    pub meta: Option<BoxMetaFields>,
+    /// Clock the deadline is measured against; `deadline_days` is converted into its units
+    pub deadline_kind: DeadlineKind,
+    /// Who referred the sender; only accepted on the sender's first box. From then on the
+    /// referrer gets `referral_share_bps` of the sender's forfeits to the treasury.
+    pub referrer: Option<Pubkey>,
+}
+
+/// One box of a `CreateBoxes`/`CreateBoxesToken` batch
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BoxEntry {
+    pub id: Pubkey,
+    pub deadline_days: u16,
+    pub amount: u64,
+    pub options: BoxOptions,
 }
 
 impl EscrowInstruction {
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
         let (&variant, rest) = input.split_first().ok_or(ProgramError::InvalidInstructionData)?;
-        
+
+        // Retired tags, rejected rather than reused: 3 and 6 (the authority-only SweepBox and
+        // SweepBoxToken), 10 (SetStakeLimits), 11 (SetMintLimits) and 13 (SetSigners)
         Ok(match variant {
             0 => Self::Initialize,
             1 => {
-                let payload = CreateBoxPayload::try_from_slice(rest)
-                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                let (payload, options) = Self::unpack_create(rest)?;
                 Self::CreateBox {
                     id: payload.id,
                     deadline_days: payload.deadline_days,
                     amount: payload.amount,
+                    options,
+                }
+            }
+            2 => {
+                let payload = Self::unpack_open(rest)?;
+                Self::OpenBox {
+                    proof: payload.proof,
+                    mint_badge: payload.mint_badge,
+                    relayer_fee: payload.relayer_fee,
                 }
             }
-            2 => Self::OpenBox,
-            3 => Self::SweepBox,
             4 => {
-                let payload = CreateBoxPayload::try_from_slice(rest)
-                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                let (payload, options) = Self::unpack_create(rest)?;
                 Self::CreateBoxToken {
                     id: payload.id,
                     deadline_days: payload.deadline_days,
                     amount: payload.amount,
+                    options,
+                }
+            }
+            5 => {
+                let payload = Self::unpack_open(rest)?;
+                Self::OpenBoxToken {
+                    proof: payload.proof,
+                    mint_badge: payload.mint_badge,
+                    relayer_fee: payload.relayer_fee,
+                }
+            }
+            7 => {
+                let entries = Vec::<BoxEntry>::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::CreateBoxes { entries }
+            }
+            8 => {
+                let entries = Vec::<BoxEntry>::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::CreateBoxesToken { entries }
+            }
+            9 => {
+                let payload = SetPausedPayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::SetPaused {
+                    create: payload.create,
+                    open: payload.open,
+                    sweep: payload.sweep,
                 }
             }
-            5 => Self::OpenBoxToken,
-            6 => Self::SweepBoxToken,
+            12 => Self::RemoveMint,
+            14 => Self::CancelConfig,
+            15 => {
+                let amount = u64::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::WithdrawTreasury { amount }
+            }
+            16 => {
+                let amount = u64::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::WithdrawTreasuryToken { amount }
+            }
+            17 => Self::ArbiterRelease,
+            18 => Self::ArbiterForfeit,
+            19 => Self::ArbiterReleaseToken,
+            20 => Self::ArbiterForfeitToken,
+            21 => Self::InitiateSweep,
+            22 => Self::InitiateSweepToken,
+            23 => {
+                let evidence = AppealEvidence::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::Appeal { evidence }
+            }
+            24 => {
+                let evidence = AppealEvidence::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::AppealToken { evidence }
+            }
+            25 => {
+                let new_id = Pubkey::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::RotateBoxId { new_id }
+            }
+            26 => {
+                let new_id = Pubkey::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::RotateBoxIdToken { new_id }
+            }
+            27 => {
+                let payload = RecoverBoxPayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::RecoverBox {
+                    new_id: payload.new_id,
+                    payout: payload.payout,
+                }
+            }
+            28 => {
+                let payload = RecoverBoxPayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::RecoverBoxToken {
+                    new_id: payload.new_id,
+                    payout: payload.payout,
+                }
+            }
+            29 => {
+                let fields = BoxMetaFields::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::SetBoxMeta { fields }
+            }
+            30 => Self::CloseBoxMeta,
+            31 => Self::ClaimReferralRewards,
+            32 => Self::MigrateAccount,
+            33 => {
+                let payload = AddMintPayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::AddMint {
+                    min_stake: payload.min_stake,
+                    max_stake: payload.max_stake,
+                    allow_freeze_authority: payload.allow_freeze_authority,
+                }
+            }
+            34 => {
+                let config = ProtocolConfig::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::QueueConfig {
+                    config: Box::new(config),
+                }
+            }
+            35 => Self::ExecuteConfig,
+            36 => Self::FinalizeSweep,
+            37 => Self::FinalizeSweepToken,
+            38 => {
+                let proof_hash = <[u8; 32]>::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::CommitFinish { proof_hash }
+            }
+            39 => {
+                let proof_hash = <[u8; 32]>::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::CommitFinishToken { proof_hash }
+            }
+            40 => {
+                let running = bool::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::SetTimer { running }
+            }
+            41 => {
+                let running = bool::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::SetTimerToken { running }
+            }
             _ => return Err(ProgramError::InvalidInstructionData),
         })
     }
+
+    /// Packs the instruction into the bytes `unpack` reads
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        match self {
+            Self::Initialize => buf.push(0),
+            Self::CreateBox {
+                id,
+                deadline_days,
+                amount,
+                options,
+            } => {
+                buf.push(1);
+                Self::pack_create(&mut buf, id, *deadline_days, *amount, options);
+            }
+            Self::OpenBox {
+                proof,
+                mint_badge,
+                relayer_fee,
+            } => {
+                buf.push(2);
+                Self::pack_open(&mut buf, proof, *mint_badge, *relayer_fee);
+            }
+            Self::FinalizeSweep => buf.push(36),
+            Self::CreateBoxToken {
+                id,
+                deadline_days,
+                amount,
+                options,
+            } => {
+                buf.push(4);
+                Self::pack_create(&mut buf, id, *deadline_days, *amount, options);
+            }
+            Self::OpenBoxToken {
+                proof,
+                mint_badge,
+                relayer_fee,
+            } => {
+                buf.push(5);
+                Self::pack_open(&mut buf, proof, *mint_badge, *relayer_fee);
+            }
+            Self::FinalizeSweepToken => buf.push(37),
+            Self::CreateBoxes { entries } => {
+                buf.push(7);
+                pack_payload(&mut buf, entries);
+            }
+            Self::CreateBoxesToken { entries } => {
+                buf.push(8);
+                pack_payload(&mut buf, entries);
+            }
+            Self::SetPaused { create, open, sweep } => {
+                buf.push(9);
+                pack_payload(
+                    &mut buf,
+                    &SetPausedPayload {
+                        create: *create,
+                        open: *open,
+                        sweep: *sweep,
+                    },
+                );
+            }
+            Self::AddMint {
+                min_stake,
+                max_stake,
+                allow_freeze_authority,
+            } => {
+                buf.push(33);
+                pack_payload(
+                    &mut buf,
+                    &AddMintPayload {
+                        min_stake: *min_stake,
+                        max_stake: *max_stake,
+                        allow_freeze_authority: *allow_freeze_authority,
+                    },
+                );
+            }
+            Self::RemoveMint => buf.push(12),
+            Self::QueueConfig { config } => {
+                buf.push(34);
+                pack_payload(&mut buf, config.as_ref());
+            }
+            Self::ExecuteConfig => buf.push(35),
+            Self::CancelConfig => buf.push(14),
+            Self::WithdrawTreasury { amount } => {
+                buf.push(15);
+                pack_payload(&mut buf, amount);
+            }
+            Self::WithdrawTreasuryToken { amount } => {
+                buf.push(16);
+                pack_payload(&mut buf, amount);
+            }
+            Self::ArbiterRelease => buf.push(17),
+            Self::ArbiterForfeit => buf.push(18),
+            Self::ArbiterReleaseToken => buf.push(19),
+            Self::ArbiterForfeitToken => buf.push(20),
+            Self::InitiateSweep => buf.push(21),
+            Self::InitiateSweepToken => buf.push(22),
+            Self::Appeal { evidence } => {
+                buf.push(23);
+                pack_payload(&mut buf, evidence);
+            }
+            Self::AppealToken { evidence } => {
+                buf.push(24);
+                pack_payload(&mut buf, evidence);
+            }
+            Self::RotateBoxId { new_id } => {
+                buf.push(25);
+                pack_payload(&mut buf, new_id);
+            }
+            Self::RotateBoxIdToken { new_id } => {
+                buf.push(26);
+                pack_payload(&mut buf, new_id);
+            }
+            Self::RecoverBox { new_id, payout } => {
+                buf.push(27);
+                pack_payload(
+                    &mut buf,
+                    &RecoverBoxPayload {
+                        new_id: *new_id,
+                        payout: *payout,
+                    },
+                );
+            }
+            Self::RecoverBoxToken { new_id, payout } => {
+                buf.push(28);
+                pack_payload(
+                    &mut buf,
+                    &RecoverBoxPayload {
+                        new_id: *new_id,
+                        payout: *payout,
+                    },
+                );
+            }
+            Self::SetBoxMeta { fields } => {
+                buf.push(29);
+                pack_payload(&mut buf, fields);
+            }
+            Self::CloseBoxMeta => buf.push(30),
+            Self::ClaimReferralRewards => buf.push(31),
+            Self::MigrateAccount => buf.push(32),
+            Self::CommitFinish { proof_hash } => {
+                buf.push(38);
+                pack_payload(&mut buf, proof_hash);
+            }
+            Self::CommitFinishToken { proof_hash } => {
+                buf.push(39);
+                pack_payload(&mut buf, proof_hash);
+            }
+            Self::SetTimer { running } => {
+                buf.push(40);
+                pack_payload(&mut buf, running);
+            }
+            Self::SetTimerToken { running } => {
+                buf.push(41);
+                pack_payload(&mut buf, running);
+            }
+        }
+
+        buf
+    }
+
+    /// Create instructions always carry their options, even the default ones
+    fn pack_create(buf: &mut Vec<u8>, id: &Pubkey, deadline_days: u16, amount: u64, options: &BoxOptions) {
+        pack_payload(
+            buf,
+            &CreateBoxPayload {
+                id: *id,
+                deadline_days,
+                amount,
+            },
+        );
+        pack_payload(buf, options);
+    }
+
+    fn pack_open(buf: &mut Vec<u8>, proof: &ReadingProof, mint_badge: bool, relayer_fee: u64) {
+        pack_payload(
+            buf,
+            &OpenBoxPayload {
+                proof: proof.clone(),
+                mint_badge,
+                relayer_fee,
+            },
+        );
+    }
+
+    /// Create instructions predating `BoxOptions` end after the amount
+    fn unpack_create(rest: &[u8]) -> Result<(CreateBoxPayload, BoxOptions), ProgramError> {
+        let mut data = rest;
+        let payload = CreateBoxPayload::deserialize(&mut data)
+            .map_err(|_| ProgramError::InvalidInstructionData)?;
+
+        let options = if data.is_empty() {
+            BoxOptions::default()
+        } else {
+            BoxOptions::try_from_slice(data)
+                .map_err(|_| ProgramError::InvalidInstructionData)?
+        };
+
+        Ok((payload, options))
+    }
+
+    /// Open instructions predating reading proofs carry no payload
+    fn unpack_open(rest: &[u8]) -> Result<OpenBoxPayload, ProgramError> {
+        if rest.is_empty() {
+            return Ok(OpenBoxPayload::default());
+        }
+
+        OpenBoxPayload::try_from_slice(rest).map_err(|_| ProgramError::InvalidInstructionData)
+    }
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -116,3 +950,1211 @@ struct CreateBoxPayload {
     amount: u64,
 }
 
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+struct OpenBoxPayload {
+    proof: ReadingProof,
+    mint_badge: bool,
+    relayer_fee: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct SetPausedPayload {
+    create: bool,
+    open: bool,
+    sweep: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct AddMintPayload {
+    min_stake: u64,
+    max_stake: u64,
+    allow_freeze_authority: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct RecoverBoxPayload {
+    new_id: Pubkey,
+    payout: Pubkey,
+}
+
+fn pack_payload<T: BorshSerialize + ?Sized>(buf: &mut Vec<u8>, payload: &T) {
+    // Writing into a Vec cannot fail
+    payload.serialize(buf).unwrap();
+}
+
+/// Optional parts of an open built with `open_box_with_options`/`open_box_token_with_options`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpenOptions {
+    /// Key the box is opened with when it is no longer the id it was created under
+    /// (after `RotateBoxId` or a guardian recovery)
+    pub id_key: Option<Pubkey>,
+    /// Reading evidence matching the box's reading mode
+    pub proof: ReadingProof,
+    /// Payer of the badge accounts' rent; a badge is minted to the sender (or to `payout`)
+    /// when set
+    pub badge_payer: Option<Pubkey>,
+    /// Recovery payout once a guardian recovery took effect. The badge goes to it instead of
+    /// the sender, and the recipient must then be the payout too.
+    pub payout: Option<Pubkey>,
+    /// Relayer wallet and its fee; token boxes pay the fee to the relayer's ATA
+    pub relayer: Option<(Pubkey, u64)>,
+}
+
+/// Program state PDA (seeds: ["program_state"])
+pub fn get_program_state_address(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"program_state"], program_id).0
+}
+
+/// Treasury PDA (seeds: ["treasury"])
+pub fn get_treasury_address(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"treasury"], program_id).0
+}
+
+/// PendingConfig PDA (seeds: ["pending_config"])
+pub fn get_pending_config_address(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"pending_config"], program_id).0
+}
+
+/// Box PDA (seeds: ["box", sender, id]) of the box created with `id`
+pub fn get_box_address(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"box", sender.as_ref(), id.as_ref()], program_id).0
+}
+
+/// TokenBox PDA (seeds: ["token_box", sender, id]) of the token box created with `id`
+pub fn get_token_box_address(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"token_box", sender.as_ref(), id.as_ref()], program_id).0
+}
+
+/// Vault authority PDA (seeds: ["vault", token_box_pda]); the vault is its ATA of the box's mint
+pub fn get_vault_authority_address(program_id: &Pubkey, token_box: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"vault", token_box.as_ref()], program_id).0
+}
+
+/// UserProfile PDA (seeds: ["profile", sender])
+pub fn get_profile_address(program_id: &Pubkey, sender: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"profile", sender.as_ref()], program_id).0
+}
+
+/// MintConfig PDA (seeds: ["mint_config", mint])
+pub fn get_mint_config_address(program_id: &Pubkey, mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"mint_config", mint.as_ref()], program_id).0
+}
+
+/// BoxMeta PDA (seeds: ["box_meta", box_pda]) of a SOL or token box
+pub fn get_box_meta_address(program_id: &Pubkey, box_pda: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"box_meta", box_pda.as_ref()], program_id).0
+}
+
+/// Badge mint PDA (seeds: ["badge", box_pda]) of a SOL or token box
+pub fn get_badge_mint_address(program_id: &Pubkey, box_pda: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"badge", box_pda.as_ref()], program_id).0
+}
+
+/// Badge authority PDA (seeds: ["badge_authority"])
+pub fn get_badge_authority_address(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"badge_authority"], program_id).0
+}
+
+/// Referral PDA (seeds: ["referral", referrer])
+pub fn get_referral_address(program_id: &Pubkey, referrer: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"referral", referrer.as_ref()], program_id).0
+}
+
+/// TokenBox PDA, vault authority PDA and vault ATA of a token box
+fn token_box_keys(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, mint: &Pubkey) -> (Pubkey, Pubkey, Pubkey) {
+    let token_box = get_token_box_address(program_id, sender, id);
+    let vault_authority = get_vault_authority_address(program_id, &token_box);
+    let vault = get_associated_token_address(&vault_authority, mint);
+    (token_box, vault_authority, vault)
+}
+
+/// Authority account of an instruction requiring authority approval. As for SPL multisig
+/// owners, the authority only signs itself when no signer set members are passed.
+fn authority_account(authority: &Pubkey, signers: &[&Pubkey]) -> AccountMeta {
+    AccountMeta::new_readonly(*authority, signers.is_empty())
+}
+
+/// Signer set members approving an instruction, passed after its regular accounts
+fn signer_accounts<'a>(signers: &'a [&Pubkey]) -> impl Iterator<Item = AccountMeta> + 'a {
+    signers.iter().map(|signer| AccountMeta::new_readonly(**signer, true))
+}
+
+/// Accounts minting the badge of an opened box to `owner`
+fn badge_accounts(program_id: &Pubkey, box_pda: &Pubkey, payer: &Pubkey, owner: &Pubkey) -> [AccountMeta; 8] {
+    let badge_mint = get_badge_mint_address(program_id, box_pda);
+    [
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(badge_mint, false),
+        AccountMeta::new_readonly(get_badge_authority_address(program_id), false),
+        AccountMeta::new(
+            get_associated_token_address_with_program_id(owner, &badge_mint, &spl_token_2022::id()),
+            false,
+        ),
+        AccountMeta::new_readonly(*owner, false),
+        AccountMeta::new_readonly(spl_token_2022::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ]
+}
+
+/// Creates an `Initialize` instruction
+pub fn initialize(program_id: &Pubkey, authority: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::Initialize.pack(),
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(get_program_state_address(program_id), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(get_treasury_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates a `CreateBox` instruction with default options
+pub fn create_box(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, deadline_days: u16, amount: u64) -> Instruction {
+    create_box_with_options(program_id, sender, id, deadline_days, amount, BoxOptions::default())
+}
+
+/// Creates a `CreateBox` instruction
+pub fn create_box_with_options(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    deadline_days: u16,
+    amount: u64,
+    options: BoxOptions,
+) -> Instruction {
+    let box_pda = get_box_address(program_id, sender, id);
+    let mut accounts = vec![
+        AccountMeta::new(*sender, true),
+        AccountMeta::new(box_pda, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_program_state_address(program_id), false),
+    ];
+
+    if options.meta.is_some() {
+        accounts.push(AccountMeta::new(get_box_meta_address(program_id, &box_pda), false));
+    }
+
+    let data = EscrowInstruction::CreateBox {
+        id: *id,
+        deadline_days,
+        amount,
+        options,
+    }
+    .pack();
+
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates an `OpenBox` instruction signed by the box id, paying the stake to `recipient`
+/// (the sender, or the recovery payout once a guardian recovery took effect)
+pub fn open_box(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, recipient: &Pubkey) -> Instruction {
+    open_box_with_options(program_id, sender, id, recipient, OpenOptions::default())
+}
+
+/// Creates an `OpenBox` instruction with a proof, badge or relayer fee
+pub fn open_box_with_options(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    recipient: &Pubkey,
+    options: OpenOptions,
+) -> Instruction {
+    let box_pda = get_box_address(program_id, sender, id);
+    let mut accounts = vec![
+        AccountMeta::new(box_pda, false),
+        AccountMeta::new(*recipient, false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_program_state_address(program_id), false),
+        AccountMeta::new_readonly(options.id_key.unwrap_or(*id), true),
+    ];
+
+    if let Some(payer) = &options.badge_payer {
+        let owner = options.payout.unwrap_or(*sender);
+        accounts.extend(badge_accounts(program_id, &box_pda, payer, &owner));
+    }
+
+    let relayer_fee = match options.relayer {
+        Some((relayer, fee)) if fee > 0 => {
+            accounts.push(AccountMeta::new(relayer, false));
+            fee
+        }
+        _ => 0,
+    };
+
+    let data = EscrowInstruction::OpenBox {
+        proof: options.proof,
+        mint_badge: options.badge_payer.is_some(),
+        relayer_fee,
+    }
+    .pack();
+
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates a `FinalizeSweep` instruction; `beneficiary` is the one the box was created under
+/// and `referrer` the one stored in the sender's profile, if any
+pub fn finalize_sweep(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    beneficiary: &Pubkey,
+    keeper: &Pubkey,
+    referrer: Option<&Pubkey>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(get_program_state_address(program_id), false),
+        AccountMeta::new(get_box_address(program_id, sender, id), false),
+        AccountMeta::new(*beneficiary, false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(*keeper, false),
+    ];
+
+    if let Some(referrer) = referrer {
+        accounts.push(AccountMeta::new(get_referral_address(program_id, referrer), false));
+    }
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::FinalizeSweep.pack(), accounts)
+}
+
+/// Creates a `CreateBoxToken` instruction with default options
+pub fn create_box_token(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    deadline_days: u16,
+    amount: u64,
+) -> Instruction {
+    create_box_token_with_options(program_id, sender, mint, id, deadline_days, amount, BoxOptions::default())
+}
+
+/// Creates a `CreateBoxToken` instruction
+pub fn create_box_token_with_options(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    deadline_days: u16,
+    amount: u64,
+    options: BoxOptions,
+) -> Instruction {
+    let (token_box, vault_authority, vault) = token_box_keys(program_id, sender, id, mint);
+    let mut accounts = vec![
+        AccountMeta::new(*sender, true),
+        AccountMeta::new(get_associated_token_address(sender, mint), false),
+        AccountMeta::new(token_box, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(vault_authority, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_program_state_address(program_id), false),
+        AccountMeta::new_readonly(get_mint_config_address(program_id, mint), false),
+    ];
+
+    if options.meta.is_some() {
+        accounts.push(AccountMeta::new(get_box_meta_address(program_id, &token_box), false));
+    }
+
+    let data = EscrowInstruction::CreateBoxToken {
+        id: *id,
+        deadline_days,
+        amount,
+        options,
+    }
+    .pack();
+
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates an `OpenBoxToken` instruction signed by the box id, paying the stake to the ATA
+/// of `recipient` (the sender, or the recovery payout once a guardian recovery took effect)
+pub fn open_box_token(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    recipient: &Pubkey,
+) -> Instruction {
+    open_box_token_with_options(program_id, sender, mint, id, recipient, OpenOptions::default())
+}
+
+/// Creates an `OpenBoxToken` instruction with a proof, badge or relayer fee
+pub fn open_box_token_with_options(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    recipient: &Pubkey,
+    options: OpenOptions,
+) -> Instruction {
+    let (token_box, vault_authority, vault) = token_box_keys(program_id, sender, id, mint);
+    let mut accounts = vec![
+        AccountMeta::new(token_box, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(get_associated_token_address(recipient, mint), false),
+        AccountMeta::new(*sender, false),
+        AccountMeta::new_readonly(vault_authority, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_program_state_address(program_id), false),
+        AccountMeta::new_readonly(options.id_key.unwrap_or(*id), true),
+    ];
+
+    if let Some(payer) = &options.badge_payer {
+        let owner = options.payout.unwrap_or(*sender);
+        accounts.extend(badge_accounts(program_id, &token_box, payer, &owner));
+    }
+
+    let relayer_fee = match options.relayer {
+        Some((relayer, fee)) if fee > 0 => {
+            accounts.push(AccountMeta::new(get_associated_token_address(&relayer, mint), false));
+            fee
+        }
+        _ => 0,
+    };
+
+    let data = EscrowInstruction::OpenBoxToken {
+        proof: options.proof,
+        mint_badge: options.badge_payer.is_some(),
+        relayer_fee,
+    }
+    .pack();
+
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates a `FinalizeSweepToken` instruction; the tokens go to the ATA of `beneficiary`
+/// (the one the box was created under), the bounty to the ATA of `keeper` and the
+/// referral share to the ATA of `referrer` (the one stored in the sender's profile, if any)
+pub fn finalize_sweep_token(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    beneficiary: &Pubkey,
+    keeper: &Pubkey,
+    referrer: Option<&Pubkey>,
+) -> Instruction {
+    let (token_box, vault_authority, vault) = token_box_keys(program_id, sender, id, mint);
+    let mut accounts = vec![
+        AccountMeta::new(get_program_state_address(program_id), false),
+        AccountMeta::new(token_box, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(get_associated_token_address(beneficiary, mint), false),
+        AccountMeta::new(*sender, false),
+        AccountMeta::new_readonly(vault_authority, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_associated_token_address(keeper, mint), false),
+    ];
+
+    if let Some(referrer) = referrer {
+        accounts.push(AccountMeta::new(get_associated_token_address(referrer, mint), false));
+    }
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::FinalizeSweepToken.pack(), accounts)
+}
+
+/// Creates a `CreateBoxes` instruction
+pub fn create_boxes(program_id: &Pubkey, sender: &Pubkey, entries: Vec<BoxEntry>) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*sender, true),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_program_state_address(program_id), false),
+    ];
+
+    for entry in &entries {
+        let box_pda = get_box_address(program_id, sender, &entry.id);
+        accounts.push(AccountMeta::new(box_pda, false));
+        if entry.options.meta.is_some() {
+            accounts.push(AccountMeta::new(get_box_meta_address(program_id, &box_pda), false));
+        }
+    }
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::CreateBoxes { entries }.pack(), accounts)
+}
+
+/// Creates a `CreateBoxesToken` instruction
+pub fn create_boxes_token(program_id: &Pubkey, sender: &Pubkey, mint: &Pubkey, entries: Vec<BoxEntry>) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*sender, true),
+        AccountMeta::new(get_associated_token_address(sender, mint), false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_program_state_address(program_id), false),
+        AccountMeta::new_readonly(get_mint_config_address(program_id, mint), false),
+    ];
+
+    for entry in &entries {
+        let (token_box, vault_authority, vault) = token_box_keys(program_id, sender, &entry.id, mint);
+        accounts.push(AccountMeta::new(token_box, false));
+        accounts.push(AccountMeta::new(vault, false));
+        accounts.push(AccountMeta::new_readonly(vault_authority, false));
+        if entry.options.meta.is_some() {
+            accounts.push(AccountMeta::new(get_box_meta_address(program_id, &token_box), false));
+        }
+    }
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::CreateBoxesToken { entries }.pack(), accounts)
+}
+
+/// Creates a `SetPaused` instruction approved by the authority or by `signers` of the signer set
+pub fn set_paused(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    signers: &[&Pubkey],
+    create: bool,
+    open: bool,
+    sweep: bool,
+) -> Instruction {
+    let mut accounts = vec![
+        authority_account(authority, signers),
+        AccountMeta::new(get_program_state_address(program_id), false),
+    ];
+    accounts.extend(signer_accounts(signers));
+
+    let data = EscrowInstruction::SetPaused { create, open, sweep }.pack();
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates an `AddMint` instruction. The authority pays the rent, so it always signs.
+pub fn add_mint(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    signers: &[&Pubkey],
+    mint: &Pubkey,
+    min_stake: u64,
+    max_stake: u64,
+    allow_freeze_authority: bool,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*authority, true),
+        AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        AccountMeta::new(get_mint_config_address(program_id, mint), false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    accounts.extend(signer_accounts(signers));
+
+    let data = EscrowInstruction::AddMint {
+        min_stake,
+        max_stake,
+        allow_freeze_authority,
+    }
+    .pack();
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates a `RemoveMint` instruction
+pub fn remove_mint(program_id: &Pubkey, authority: &Pubkey, signers: &[&Pubkey], mint: &Pubkey) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*authority, signers.is_empty()),
+        AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        AccountMeta::new(get_mint_config_address(program_id, mint), false),
+    ];
+    accounts.extend(signer_accounts(signers));
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::RemoveMint.pack(), accounts)
+}
+
+/// Creates a `QueueConfig` instruction; `payer` pays the PendingConfig rent
+pub fn queue_config(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    signers: &[&Pubkey],
+    payer: &Pubkey,
+    config: ProtocolConfig,
+) -> Instruction {
+    let mut accounts = vec![
+        authority_account(authority, signers),
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        AccountMeta::new(get_pending_config_address(program_id), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    accounts.extend(signer_accounts(signers));
+
+    let data = EscrowInstruction::QueueConfig {
+        config: Box::new(config),
+    }
+    .pack();
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates an `ExecuteConfig` instruction; `payer` is the account that queued the config
+pub fn execute_config(program_id: &Pubkey, payer: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::ExecuteConfig.pack(),
+        vec![
+            AccountMeta::new(get_program_state_address(program_id), false),
+            AccountMeta::new(get_pending_config_address(program_id), false),
+            AccountMeta::new(*payer, false),
+        ],
+    )
+}
+
+/// Creates a `CancelConfig` instruction; `payer` is the account that queued the config
+pub fn cancel_config(program_id: &Pubkey, authority: &Pubkey, signers: &[&Pubkey], payer: &Pubkey) -> Instruction {
+    let mut accounts = vec![
+        authority_account(authority, signers),
+        AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        AccountMeta::new(get_pending_config_address(program_id), false),
+        AccountMeta::new(*payer, false),
+    ];
+    accounts.extend(signer_accounts(signers));
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::CancelConfig.pack(), accounts)
+}
+
+/// Creates a `WithdrawTreasury` instruction
+pub fn withdraw_treasury(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    signers: &[&Pubkey],
+    destination: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let mut accounts = vec![
+        authority_account(authority, signers),
+        AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        AccountMeta::new(get_treasury_address(program_id), false),
+        AccountMeta::new(*destination, false),
+    ];
+    accounts.extend(signer_accounts(signers));
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::WithdrawTreasury { amount }.pack(), accounts)
+}
+
+/// Creates a `WithdrawTreasuryToken` instruction moving tokens from the treasury's ATA of
+/// `mint` to the ATA of `destination`
+pub fn withdraw_treasury_token(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    signers: &[&Pubkey],
+    mint: &Pubkey,
+    destination: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let treasury = get_treasury_address(program_id);
+    let mut accounts = vec![
+        authority_account(authority, signers),
+        AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        AccountMeta::new_readonly(treasury, false),
+        AccountMeta::new(get_associated_token_address(&treasury, mint), false),
+        AccountMeta::new(get_associated_token_address(destination, mint), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+    accounts.extend(signer_accounts(signers));
+
+    let data = EscrowInstruction::WithdrawTreasuryToken { amount }.pack();
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates an `ArbiterRelease` instruction; `recipient` is the sender or the recovery payout
+pub fn arbiter_release(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    arbiter: &Pubkey,
+    recipient: &Pubkey,
+) -> Instruction {
+    arbiter_judgement(program_id, sender, id, arbiter, recipient, EscrowInstruction::ArbiterRelease)
+}
+
+/// Creates an `ArbiterForfeit` instruction; `beneficiary` is the one the box was created under
+/// and `referrer` the one stored in the sender's profile, if any
+pub fn arbiter_forfeit(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    arbiter: &Pubkey,
+    beneficiary: &Pubkey,
+    referrer: Option<&Pubkey>,
+) -> Instruction {
+    let mut instruction = arbiter_judgement(program_id, sender, id, arbiter, beneficiary, EscrowInstruction::ArbiterForfeit);
+    if let Some(referrer) = referrer {
+        instruction.accounts.push(AccountMeta::new(get_referral_address(program_id, referrer), false));
+    }
+    instruction
+}
+
+fn arbiter_judgement(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    arbiter: &Pubkey,
+    destination: &Pubkey,
+    instruction: EscrowInstruction,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &instruction.pack(),
+        vec![
+            AccountMeta::new(get_box_address(program_id, sender, id), false),
+            AccountMeta::new_readonly(*arbiter, true),
+            AccountMeta::new(*destination, false),
+            AccountMeta::new(get_profile_address(program_id, sender), false),
+            AccountMeta::new(get_program_state_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates an `ArbiterReleaseToken` instruction paying the ATA of `recipient`
+/// (the sender or the recovery payout)
+pub fn arbiter_release_token(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    arbiter: &Pubkey,
+    recipient: &Pubkey,
+) -> Instruction {
+    arbiter_judgement_token(program_id, sender, mint, id, arbiter, recipient, EscrowInstruction::ArbiterReleaseToken)
+}
+
+/// Creates an `ArbiterForfeitToken` instruction paying the ATA of `beneficiary`, and the
+/// referral share to the ATA of `referrer` (the one stored in the sender's profile, if any)
+pub fn arbiter_forfeit_token(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    arbiter: &Pubkey,
+    beneficiary: &Pubkey,
+    referrer: Option<&Pubkey>,
+) -> Instruction {
+    let mut instruction =
+        arbiter_judgement_token(program_id, sender, mint, id, arbiter, beneficiary, EscrowInstruction::ArbiterForfeitToken);
+    if let Some(referrer) = referrer {
+        instruction.accounts.push(AccountMeta::new(get_associated_token_address(referrer, mint), false));
+    }
+    instruction
+}
+
+fn arbiter_judgement_token(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    arbiter: &Pubkey,
+    destination: &Pubkey,
+    instruction: EscrowInstruction,
+) -> Instruction {
+    let (token_box, vault_authority, vault) = token_box_keys(program_id, sender, id, mint);
+    Instruction::new_with_bytes(
+        *program_id,
+        &instruction.pack(),
+        vec![
+            AccountMeta::new(token_box, false),
+            AccountMeta::new_readonly(*arbiter, true),
+            AccountMeta::new(vault, false),
+            AccountMeta::new(get_associated_token_address(destination, mint), false),
+            AccountMeta::new(*sender, false),
+            AccountMeta::new_readonly(vault_authority, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(get_profile_address(program_id, sender), false),
+            AccountMeta::new(get_program_state_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates an `InitiateSweep` instruction
+pub fn initiate_sweep(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::InitiateSweep.pack(),
+        vec![
+            AccountMeta::new_readonly(get_program_state_address(program_id), false),
+            AccountMeta::new(get_box_address(program_id, sender, id), false),
+        ],
+    )
+}
+
+/// Creates an `InitiateSweepToken` instruction
+pub fn initiate_sweep_token(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::InitiateSweepToken.pack(),
+        vec![
+            AccountMeta::new_readonly(get_program_state_address(program_id), false),
+            AccountMeta::new(get_token_box_address(program_id, sender, id), false),
+        ],
+    )
+}
+
+/// Creates an `Appeal` instruction; `attestor` signs for `AppealEvidence::Attestation`.
+/// `recipient` is the sender, or the recovery payout once a guardian recovery took effect.
+pub fn appeal(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    recipient: &Pubkey,
+    evidence: AppealEvidence,
+    attestor: Option<&Pubkey>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(get_box_address(program_id, sender, id), false),
+        AccountMeta::new_readonly(*sender, true),
+        AccountMeta::new(*recipient, false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_program_state_address(program_id), false),
+    ];
+    accounts.extend(attestor.map(|attestor| AccountMeta::new_readonly(*attestor, true)));
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::Appeal { evidence }.pack(), accounts)
+}
+
+/// Creates an `AppealToken` instruction; `recipient` and `attestor` as for `appeal`
+pub fn appeal_token(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    mint: &Pubkey,
+    id: &Pubkey,
+    recipient: &Pubkey,
+    evidence: AppealEvidence,
+    attestor: Option<&Pubkey>,
+) -> Instruction {
+    let (token_box, vault_authority, vault) = token_box_keys(program_id, sender, id, mint);
+    let mut accounts = vec![
+        AccountMeta::new(token_box, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(get_associated_token_address(recipient, mint), false),
+        AccountMeta::new(*sender, true),
+        AccountMeta::new_readonly(vault_authority, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(get_profile_address(program_id, sender), false),
+        AccountMeta::new(get_program_state_address(program_id), false),
+    ];
+    accounts.extend(attestor.map(|attestor| AccountMeta::new_readonly(*attestor, true)));
+
+    Instruction::new_with_bytes(*program_id, &EscrowInstruction::AppealToken { evidence }.pack(), accounts)
+}
+
+/// Creates a `RotateBoxId` instruction for the box created with `id`
+pub fn rotate_box_id(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, new_id: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::RotateBoxId { new_id: *new_id }.pack(),
+        vec![
+            AccountMeta::new(get_box_address(program_id, sender, id), false),
+            AccountMeta::new_readonly(*sender, true),
+            AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates a `RotateBoxIdToken` instruction for the token box created with `id`
+pub fn rotate_box_id_token(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, new_id: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::RotateBoxIdToken { new_id: *new_id }.pack(),
+        vec![
+            AccountMeta::new(get_token_box_address(program_id, sender, id), false),
+            AccountMeta::new_readonly(*sender, true),
+            AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates a `RecoverBox` instruction signed by `guardians`
+pub fn recover_box(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    guardians: &[&Pubkey],
+    new_id: &Pubkey,
+    payout: &Pubkey,
+) -> Instruction {
+    let mut accounts = vec![AccountMeta::new(get_box_address(program_id, sender, id), false)];
+    accounts.extend(signer_accounts(guardians));
+
+    let data = EscrowInstruction::RecoverBox {
+        new_id: *new_id,
+        payout: *payout,
+    }
+    .pack();
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates a `RecoverBoxToken` instruction signed by `guardians`
+pub fn recover_box_token(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    id: &Pubkey,
+    guardians: &[&Pubkey],
+    new_id: &Pubkey,
+    payout: &Pubkey,
+) -> Instruction {
+    let mut accounts = vec![AccountMeta::new(get_token_box_address(program_id, sender, id), false)];
+    accounts.extend(signer_accounts(guardians));
+
+    let data = EscrowInstruction::RecoverBoxToken {
+        new_id: *new_id,
+        payout: *payout,
+    }
+    .pack();
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Creates a `SetBoxMeta` instruction; `box_pda` is a Box or TokenBox PDA
+pub fn set_box_meta(program_id: &Pubkey, sender: &Pubkey, box_pda: &Pubkey, fields: BoxMetaFields) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::SetBoxMeta { fields }.pack(),
+        vec![
+            AccountMeta::new(*sender, true),
+            AccountMeta::new_readonly(*box_pda, false),
+            AccountMeta::new(get_box_meta_address(program_id, box_pda), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Creates a `CloseBoxMeta` instruction; `box_pda` is a Box or TokenBox PDA
+pub fn close_box_meta(program_id: &Pubkey, sender: &Pubkey, box_pda: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::CloseBoxMeta.pack(),
+        vec![
+            AccountMeta::new(*sender, true),
+            AccountMeta::new_readonly(*box_pda, false),
+            AccountMeta::new(get_box_meta_address(program_id, box_pda), false),
+        ],
+    )
+}
+
+/// Creates a `ClaimReferralRewards` instruction
+pub fn claim_referral_rewards(program_id: &Pubkey, referrer: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::ClaimReferralRewards.pack(),
+        vec![
+            AccountMeta::new(*referrer, true),
+            AccountMeta::new(get_referral_address(program_id, referrer), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Creates a `MigrateAccount` instruction for the program state
+pub fn migrate_program_state(program_id: &Pubkey, payer: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::MigrateAccount.pack(),
+        vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(get_program_state_address(program_id), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(get_treasury_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates a `MigrateAccount` instruction for a box; `box_pda` is a Box or TokenBox PDA of `sender`
+pub fn migrate_box(program_id: &Pubkey, payer: &Pubkey, sender: &Pubkey, box_pda: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::MigrateAccount.pack(),
+        vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(*box_pda, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(get_program_state_address(program_id), false),
+            AccountMeta::new(get_profile_address(program_id, sender), false),
+        ],
+    )
+}
+
+/// Creates a `CommitFinish` instruction committing the box created with `id` to `proof`,
+/// signed by `id_key`, the key currently controlling the box
+pub fn commit_finish(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, id_key: &Pubkey, proof: &ReadingProof) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::CommitFinish { proof_hash: proof.hash() }.pack(),
+        vec![
+            AccountMeta::new(get_box_address(program_id, sender, id), false),
+            AccountMeta::new_readonly(*id_key, true),
+            AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates a `CommitFinishToken` instruction committing the token box created with `id` to
+/// `proof`, signed by `id_key`, the key currently controlling the box
+pub fn commit_finish_token(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, id_key: &Pubkey, proof: &ReadingProof) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::CommitFinishToken { proof_hash: proof.hash() }.pack(),
+        vec![
+            AccountMeta::new(get_token_box_address(program_id, sender, id), false),
+            AccountMeta::new_readonly(*id_key, true),
+            AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates a `SetTimer` instruction starting or stopping the timer of the box created with
+/// `id`, signed by `id_key`, the key currently controlling the box
+pub fn set_timer(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, id_key: &Pubkey, running: bool) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::SetTimer { running }.pack(),
+        vec![
+            AccountMeta::new(get_box_address(program_id, sender, id), false),
+            AccountMeta::new_readonly(*id_key, true),
+            AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        ],
+    )
+}
+
+/// Creates a `SetTimerToken` instruction starting or stopping the timer of the token box
+/// created with `id`, signed by `id_key`, the key currently controlling the box
+pub fn set_timer_token(program_id: &Pubkey, sender: &Pubkey, id: &Pubkey, id_key: &Pubkey, running: bool) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &EscrowInstruction::SetTimerToken { running }.pack(),
+        vec![
+            AccountMeta::new(get_token_box_address(program_id, sender, id), false),
+            AccountMeta::new_readonly(*id_key, true),
+            AccountMeta::new_readonly(get_program_state_address(program_id), false),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_options() -> BoxOptions {
+        BoxOptions {
+            arbiter: Some(Pubkey::new_unique()),
+            guardians: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+            guardian_threshold: 2,
+            content_hash: Some([7; 32]),
+            title: Some("Middlemarch".to_string()),
+            reading_mode: ReadingMode::Checkpoints {
+                count: 3,
+                commitment_root: [9; 32],
+            },
+            meta: Some(BoxMetaFields {
+                title: "Middlemarch".to_string(),
+                author: "George Eliot".to_string(),
+                note: "book club".to_string(),
+                language: "en".to_string(),
+            }),
+            deadline_kind: DeadlineKind::Slot,
+            referrer: Some(Pubkey::new_unique()),
+        }
+    }
+
+    fn timer_options() -> BoxOptions {
+        BoxOptions {
+            reading_mode: ReadingMode::Timer { target_hours: 12 },
+            deadline_kind: DeadlineKind::Epoch,
+            ..BoxOptions::default()
+        }
+    }
+
+    fn entry(options: BoxOptions) -> BoxEntry {
+        BoxEntry {
+            id: Pubkey::new_unique(),
+            deadline_days: 30,
+            amount: 1_000_000,
+            options,
+        }
+    }
+
+    fn every_instruction() -> Vec<EscrowInstruction> {
+        let mut config = ProtocolConfig::new(Pubkey::new_unique(), Pubkey::new_unique());
+        config.signers[0] = Pubkey::new_unique();
+        config.signer_count = 1;
+        config.threshold = 1;
+        config.referral_share_bps = 2_500;
+
+        let mut instructions = vec![EscrowInstruction::Initialize];
+        for options in [BoxOptions::default(), full_options(), timer_options()] {
+            let BoxEntry { id, deadline_days, amount, options } = entry(options);
+            instructions.push(EscrowInstruction::CreateBox { id, deadline_days, amount, options: options.clone() });
+            instructions.push(EscrowInstruction::CreateBoxToken { id, deadline_days, amount, options });
+        }
+        for proof in [
+            ReadingProof::Empty,
+            ReadingProof::Checkpoints { codes: vec![[1; 32], [2; 32]] },
+        ] {
+            instructions.push(EscrowInstruction::CommitFinish { proof_hash: proof.hash() });
+            instructions.push(EscrowInstruction::CommitFinishToken { proof_hash: proof.hash() });
+            instructions.push(EscrowInstruction::OpenBox {
+                proof: proof.clone(),
+                mint_badge: true,
+                relayer_fee: 5_000,
+            });
+            instructions.push(EscrowInstruction::OpenBoxToken {
+                proof,
+                mint_badge: false,
+                relayer_fee: 0,
+            });
+        }
+        instructions.extend([
+            EscrowInstruction::FinalizeSweep,
+            EscrowInstruction::FinalizeSweepToken,
+            EscrowInstruction::CreateBoxes {
+                entries: vec![entry(BoxOptions::default()), entry(full_options())],
+            },
+            EscrowInstruction::CreateBoxesToken {
+                entries: vec![entry(timer_options())],
+            },
+            EscrowInstruction::SetPaused {
+                create: true,
+                open: false,
+                sweep: true,
+            },
+            EscrowInstruction::AddMint {
+                min_stake: 1,
+                max_stake: 0,
+                allow_freeze_authority: true,
+            },
+            EscrowInstruction::RemoveMint,
+            EscrowInstruction::QueueConfig {
+                config: Box::new(config),
+            },
+            EscrowInstruction::ExecuteConfig,
+            EscrowInstruction::CancelConfig,
+            EscrowInstruction::WithdrawTreasury { amount: 42 },
+            EscrowInstruction::WithdrawTreasuryToken { amount: 43 },
+            EscrowInstruction::ArbiterRelease,
+            EscrowInstruction::ArbiterForfeit,
+            EscrowInstruction::ArbiterReleaseToken,
+            EscrowInstruction::ArbiterForfeitToken,
+            EscrowInstruction::InitiateSweep,
+            EscrowInstruction::InitiateSweepToken,
+            EscrowInstruction::Appeal {
+                evidence: AppealEvidence::Attestation,
+            },
+            EscrowInstruction::AppealToken {
+                evidence: AppealEvidence::CheckpointReveal { codes: vec![[3; 32]] },
+            },
+            EscrowInstruction::RotateBoxId {
+                new_id: Pubkey::new_unique(),
+            },
+            EscrowInstruction::RotateBoxIdToken {
+                new_id: Pubkey::new_unique(),
+            },
+            EscrowInstruction::RecoverBox {
+                new_id: Pubkey::new_unique(),
+                payout: Pubkey::new_unique(),
+            },
+            EscrowInstruction::RecoverBoxToken {
+                new_id: Pubkey::new_unique(),
+                payout: Pubkey::new_unique(),
+            },
+            EscrowInstruction::SetBoxMeta {
+                fields: full_options().meta.unwrap(),
+            },
+            EscrowInstruction::CloseBoxMeta,
+            EscrowInstruction::ClaimReferralRewards,
+            EscrowInstruction::MigrateAccount,
+            EscrowInstruction::SetTimer { running: true },
+            EscrowInstruction::SetTimerToken { running: false },
+        ]);
+        instructions
+    }
+
+    #[test]
+    fn unpack_reverses_pack() {
+        for instruction in every_instruction() {
+            assert_eq!(EscrowInstruction::unpack(&instruction.pack()), Ok(instruction));
+        }
+    }
+
+    #[test]
+    fn open_without_payload_is_a_plain_open() {
+        assert_eq!(
+            EscrowInstruction::unpack(&[2]),
+            Ok(EscrowInstruction::OpenBox {
+                proof: ReadingProof::Empty,
+                mint_badge: false,
+                relayer_fee: 0,
+            })
+        );
+        assert_eq!(
+            EscrowInstruction::unpack(&[5]),
+            Ok(EscrowInstruction::OpenBoxToken {
+                proof: ReadingProof::Empty,
+                mint_badge: false,
+                relayer_fee: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn create_without_options_uses_the_defaults() {
+        let id = Pubkey::new_unique();
+        let mut data = vec![1];
+        pack_payload(
+            &mut data,
+            &CreateBoxPayload {
+                id,
+                deadline_days: 7,
+                amount: 500,
+            },
+        );
+
+        assert_eq!(
+            EscrowInstruction::unpack(&data),
+            Ok(EscrowInstruction::CreateBox {
+                id,
+                deadline_days: 7,
+                amount: 500,
+                options: BoxOptions::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn retired_and_unknown_tags_are_rejected() {
+        for data in [&[][..], &[3], &[6], &[10], &[11], &[13], &[38], &[255]] {
+            assert_eq!(EscrowInstruction::unpack(data), Err(ProgramError::InvalidInstructionData));
+        }
+    }
+
+    #[test]
+    fn badge_goes_to_the_sender_rather_than_the_recipient() {
+        let program_id = Pubkey::new_unique();
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let recipient = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let options = OpenOptions {
+            badge_payer: Some(Pubkey::new_unique()),
+            ..OpenOptions::default()
+        };
+
+        let open = open_box_with_options(&program_id, &sender, &id, &recipient, options.clone());
+        assert_eq!(open.accounts[1].pubkey, recipient);
+        assert_eq!(open.accounts[9].pubkey, sender);
+
+        let open_token = open_box_token_with_options(&program_id, &sender, &mint, &id, &recipient, options.clone());
+        assert_eq!(open_token.accounts[2].pubkey, get_associated_token_address(&recipient, &mint));
+        assert_eq!(open_token.accounts[13].pubkey, sender);
+
+        let payout = Pubkey::new_unique();
+        let recovered = OpenOptions {
+            payout: Some(payout),
+            ..options
+        };
+        let open = open_box_with_options(&program_id, &sender, &id, &payout, recovered);
+        assert_eq!(open.accounts[9].pubkey, payout);
+    }
+}
@@ -6,9 +6,14 @@ use solana_program::{
 };
 
 pub mod error;
+pub mod event;
 pub mod instruction;
+#[cfg(not(target_os = "solana"))]
+pub mod nonce;
 pub mod processor;
 pub mod state;
+#[cfg(test)]
+mod test_stubs;
 
 use processor::Processor;
 
@@ -0,0 +1,154 @@
+//! Client tooling for durable-nonce `OpenBox` transactions.
+//!
+//! A phone that is offline when reading is finished can sign an open transaction against a
+//! durable nonce instead of a recent blockhash. The signed transaction stays valid until the
+//! nonce is advanced, so it can be stored and submitted later by the app or any relayer; the
+//! program accepts it as long as the box can still be opened when it lands.
+//!
+//! Nonce accounts are derived from the user's wallet with `create_with_seed`, so a user needs
+//! no extra keypair per nonce and every nonce account can be found again from the wallet alone.
+//! Submitting a transaction advances its nonce, so each pending pre-signed transaction needs
+//! its own nonce account (`index`).
+//!
+//! A pre-signed open is bound to one relayer: its key is the fee payer and the fee recipient
+//! of every open, and both are covered by the signatures. If that relayer may be unavailable,
+//! pre-sign a second transaction for another relayer against another nonce account. Whichever
+//! lands first opens the box and the other then fails, so the reader pays only one fee.
+
+use solana_program::{
+    hash::Hash,
+    instruction::Instruction,
+    message::Message,
+    pubkey::{Pubkey, PubkeyError},
+    system_instruction, system_program,
+};
+
+/// Size of a system nonce account
+pub const NONCE_ACCOUNT_LEN: usize = 80;
+
+/// `SystemInstruction::AdvanceNonceAccount`, bincode encoded
+const ADVANCE_NONCE_ACCOUNT_DATA: [u8; 4] = [4, 0, 0, 0];
+
+/// Offsets in a nonce account (version tag, state tag, authority, durable nonce, fee calculator)
+const NONCE_STATE_OFFSET: usize = 4;
+const NONCE_STATE_INITIALIZED: u8 = 1;
+const NONCE_AUTHORITY_OFFSET: usize = NONCE_STATE_OFFSET + 4;
+const NONCE_HASH_OFFSET: usize = NONCE_AUTHORITY_OFFSET + 32;
+
+fn nonce_seed(index: u8) -> String {
+    format!("escrow-nonce-{index}")
+}
+
+/// Address of the `index`th nonce account of `owner`
+pub fn nonce_account_address(owner: &Pubkey, index: u8) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_with_seed(owner, &nonce_seed(index), &system_program::id())
+}
+
+/// Instructions creating the `index`th nonce account of `owner`, funded by `owner` with
+/// `lamports` (the rent-exempt minimum for `NONCE_ACCOUNT_LEN` bytes). `authority` signs every
+/// transaction using the nonce; usually the app key that also signs the opens.
+pub fn create_nonce_account(
+    owner: &Pubkey,
+    index: u8,
+    authority: &Pubkey,
+    lamports: u64,
+) -> Result<Vec<Instruction>, PubkeyError> {
+    let nonce_account = nonce_account_address(owner, index)?;
+    Ok(system_instruction::create_nonce_account_with_seed(
+        owner,
+        &nonce_account,
+        owner,
+        &nonce_seed(index),
+        authority,
+        lamports,
+    ))
+}
+
+/// Instruction moving `lamports` out of the `index`th nonce account of `owner` to `destination`;
+/// withdrawing the whole balance closes the account
+pub fn withdraw_nonce_account(
+    owner: &Pubkey,
+    index: u8,
+    authority: &Pubkey,
+    destination: &Pubkey,
+    lamports: u64,
+) -> Result<Instruction, PubkeyError> {
+    let nonce_account = nonce_account_address(owner, index)?;
+    Ok(system_instruction::withdraw_nonce_account(
+        &nonce_account,
+        authority,
+        destination,
+        lamports,
+    ))
+}
+
+/// Authority and current durable nonce of a nonce account, `None` if it is not initialized
+pub fn nonce_state(data: &[u8]) -> Option<(Pubkey, Hash)> {
+    if data.len() < NONCE_ACCOUNT_LEN || data[NONCE_STATE_OFFSET] != NONCE_STATE_INITIALIZED {
+        return None;
+    }
+
+    let authority = Pubkey::try_from(&data[NONCE_AUTHORITY_OFFSET..NONCE_HASH_OFFSET]).ok()?;
+    let durable_nonce = Hash::new_from_array(data[NONCE_HASH_OFFSET..NONCE_HASH_OFFSET + 32].try_into().ok()?);
+    Some((authority, durable_nonce))
+}
+
+/// Message opening boxes against a durable nonce, ready to be signed by `fee_payer`,
+/// the nonce authority and the box id keys. `durable_nonce` is read with `nonce_state`.
+/// For a relayed open `fee_payer` is the relayer the opens pay, and only it can submit the
+/// message.
+pub fn open_box_message(
+    open_instructions: Vec<Instruction>,
+    fee_payer: &Pubkey,
+    nonce_account: &Pubkey,
+    nonce_authority: &Pubkey,
+    durable_nonce: Hash,
+) -> Message {
+    let mut message = Message::new_with_nonce(
+        open_instructions,
+        Some(fee_payer),
+        nonce_account,
+        nonce_authority,
+    );
+    message.recent_blockhash = durable_nonce;
+    message
+}
+
+/// Whether an instruction advances a durable nonce (the first instruction of a nonce transaction)
+pub fn is_advance_nonce(program_id: &Pubkey, data: &[u8]) -> bool {
+    *program_id == system_program::id() && data == ADVANCE_NONCE_ACCOUNT_DATA
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::nonce::state::{Data, DurableNonce, State, Versions};
+
+    #[test]
+    fn advance_nonce_data_matches_the_system_program() {
+        let advance = system_instruction::advance_nonce_account(&Pubkey::new_unique(), &Pubkey::new_unique());
+        assert_eq!(advance.data, ADVANCE_NONCE_ACCOUNT_DATA);
+        assert!(is_advance_nonce(&advance.program_id, &advance.data));
+
+        let owner = Pubkey::new_unique();
+        let withdraw = withdraw_nonce_account(&owner, 0, &owner, &owner, 1).unwrap();
+        assert!(!is_advance_nonce(&withdraw.program_id, &withdraw.data));
+        assert!(!is_advance_nonce(&Pubkey::new_unique(), &advance.data));
+    }
+
+    #[test]
+    fn nonce_state_reads_system_nonce_accounts() {
+        let authority = Pubkey::new_unique();
+        let durable_nonce = DurableNonce::from_blockhash(&Hash::new_unique());
+        let initialized = Versions::new(State::Initialized(Data::new(authority, durable_nonce, 5_000)));
+        let data = bincode::serialize(&initialized).unwrap();
+
+        assert_eq!(data.len(), NONCE_ACCOUNT_LEN);
+        assert_eq!(nonce_state(&data), Some((authority, *durable_nonce.as_hash())));
+        assert_eq!(nonce_state(&data[..NONCE_ACCOUNT_LEN - 1]), None);
+
+        let mut uninitialized = bincode::serialize(&Versions::new(State::Uninitialized)).unwrap();
+        uninitialized.resize(NONCE_ACCOUNT_LEN, 0);
+        assert_eq!(nonce_state(&uninitialized), None);
+    }
+}
@@ -14,11 +14,70 @@ use solana_program::{
 use spl_token::instruction as token_instruction;
 use spl_associated_token_account::instruction as ata_instruction;
 use spl_associated_token_account::get_associated_token_address;
+use solana_program_pack::Pack;
+use spl_token_2022::{
+    extension::{metadata_pointer, ExtensionType},
+    instruction as token_2022_instruction,
+    state::Mint as Token2022Mint,
+};
+use spl_token_metadata_interface::{
+    instruction as metadata_instruction,
+    state::{Field, TokenMetadata},
+};
 
-use crate::{error::EscrowError, instruction::EscrowInstruction, state::{Box, ProgramState, TokenBox}};
+use crate::{
+    error::EscrowError,
+    event::{
+        AccountMigrated, AuthorityChanged, BadgeMinted, BoxCreated, BoxIdRotated, BoxMetaClosed, BoxMetaSet, BoxOpened,
+        BoxSwept, ConfigCancelled, ConfigExecuted, ConfigQueued, EscrowEvent, FinishCommitted, MintConfigured, MintRemoved,
+        PauseChanged, RecoveryQueued, ReferralAccrued, ReferralRewardsClaimed, ReferralSkipped, ReferrerSet,
+        RelayerPaid, SweepInitiated, TimerSet,
+        TokenBoxCreated, TokenBoxOpened, TokenBoxSwept, TreasuryTokenWithdrawn, TreasuryWithdrawn,
+    },
+    instruction::{
+        AppealEvidence, BoxEntry, BoxOptions, EscrowInstruction, ReadingProof, MAX_BATCH_BOXES,
+    },
+    state::{
+        AccountKind, BookRef, Box, BoxMeta, BoxMetaFields, BoxTerms, FinishCommitment, GuardianSet, LegacyBox, LegacyProgramState, LegacyTokenBox, MintConfig, PendingConfig, ProgramState, ProtocolConfig,
+        ReadingMode, ReadingTimer, Recovery, Referral, StakeLimits, TokenBox, UserProfile, CONFIG_TIMELOCK, MAX_GUARDIANS, MAX_SIGNERS,
+        BADGE_DEFAULT_NAME, BADGE_SYMBOL, RECOVERY_DELAY,
+    },
+};
 
 pub struct Processor;
 
+/// Accounts and mint settings shared by every token box created in one instruction
+struct TokenBoxSetup<'b, 'a> {
+    sender: &'b AccountInfo<'a>,
+    sender_token_account: &'b AccountInfo<'a>,
+    mint: &'b AccountInfo<'a>,
+    token_program: &'b AccountInfo<'a>,
+    associated_token_program: &'b AccountInfo<'a>,
+    system_program: &'b AccountInfo<'a>,
+    stake_limits: StakeLimits, // from the mint's MintConfig
+}
+
+/// Vault of a token box being settled, signed for by its vault authority PDA
+struct VaultAccounts<'b, 'a> {
+    token_box: &'b AccountInfo<'a>,
+    vault_ata: &'b AccountInfo<'a>,
+    vault_authority: &'b AccountInfo<'a>,
+    vault_bump: u8,
+    token_program: &'b AccountInfo<'a>,
+}
+
+/// Accounts `OpenBox`/`OpenBoxToken` take after the box id key when a badge is requested
+struct BadgeAccounts<'b, 'a> {
+    payer: &'b AccountInfo<'a>,
+    badge_mint: &'b AccountInfo<'a>,
+    badge_authority: &'b AccountInfo<'a>,
+    owner_token_account: &'b AccountInfo<'a>,
+    owner: &'b AccountInfo<'a>,
+    token_program: &'b AccountInfo<'a>,
+    associated_token_program: &'b AccountInfo<'a>,
+    system_program: &'b AccountInfo<'a>,
+}
+
 impl Processor {
     pub fn process(
         program_id: &Pubkey,
@@ -31,23 +90,115 @@ impl Processor {
             EscrowInstruction::Initialize => {
                 Self::process_initialize(program_id, accounts)
             }
-            EscrowInstruction::CreateBox { id, deadline_days, amount } => {
-                Self::process_create_box(program_id, accounts, id, deadline_days, amount)
+            EscrowInstruction::CreateBox { id, deadline_days, amount, options } => {
+                let entry = BoxEntry { id, deadline_days, amount, options };
+                Self::process_create_box(program_id, accounts, entry)
+            }
+            EscrowInstruction::OpenBox { proof, mint_badge, relayer_fee } => {
+                Self::process_open_box(program_id, accounts, proof, mint_badge, relayer_fee)
+            }
+            EscrowInstruction::FinalizeSweep => {
+                Self::process_finalize_sweep(program_id, accounts)
+            }
+            EscrowInstruction::CreateBoxToken { id, deadline_days, amount, options } => {
+                let entry = BoxEntry { id, deadline_days, amount, options };
+                Self::process_create_box_token(program_id, accounts, entry)
+            }
+            EscrowInstruction::OpenBoxToken { proof, mint_badge, relayer_fee } => {
+                Self::process_open_box_token(program_id, accounts, proof, mint_badge, relayer_fee)
+            }
+            EscrowInstruction::FinalizeSweepToken => {
+                Self::process_finalize_sweep_token(program_id, accounts)
+            }
+            EscrowInstruction::CreateBoxes { entries } => {
+                Self::process_create_boxes(program_id, accounts, entries)
+            }
+            EscrowInstruction::CreateBoxesToken { entries } => {
+                Self::process_create_boxes_token(program_id, accounts, entries)
+            }
+            EscrowInstruction::SetPaused { create, open, sweep } => {
+                Self::process_set_paused(program_id, accounts, create, open, sweep)
+            }
+            EscrowInstruction::AddMint { min_stake, max_stake, allow_freeze_authority } => {
+                Self::process_add_mint(program_id, accounts, min_stake, max_stake, allow_freeze_authority)
+            }
+            EscrowInstruction::RemoveMint => {
+                Self::process_remove_mint(program_id, accounts)
+            }
+            EscrowInstruction::QueueConfig { config } => {
+                Self::process_queue_config(program_id, accounts, *config)
+            }
+            EscrowInstruction::ExecuteConfig => {
+                Self::process_execute_config(program_id, accounts)
+            }
+            EscrowInstruction::CancelConfig => {
+                Self::process_cancel_config(program_id, accounts)
+            }
+            EscrowInstruction::WithdrawTreasury { amount } => {
+                Self::process_withdraw_treasury(program_id, accounts, amount)
+            }
+            EscrowInstruction::WithdrawTreasuryToken { amount } => {
+                Self::process_withdraw_treasury_token(program_id, accounts, amount)
+            }
+            EscrowInstruction::ArbiterRelease => {
+                Self::process_arbiter_decision(program_id, accounts, true)
+            }
+            EscrowInstruction::ArbiterForfeit => {
+                Self::process_arbiter_decision(program_id, accounts, false)
+            }
+            EscrowInstruction::ArbiterReleaseToken => {
+                Self::process_arbiter_decision_token(program_id, accounts, true)
+            }
+            EscrowInstruction::ArbiterForfeitToken => {
+                Self::process_arbiter_decision_token(program_id, accounts, false)
+            }
+            EscrowInstruction::InitiateSweep => {
+                Self::process_initiate_sweep(program_id, accounts)
+            }
+            EscrowInstruction::InitiateSweepToken => {
+                Self::process_initiate_sweep_token(program_id, accounts)
+            }
+            EscrowInstruction::Appeal { evidence } => {
+                Self::process_appeal(program_id, accounts, evidence)
+            }
+            EscrowInstruction::AppealToken { evidence } => {
+                Self::process_appeal_token(program_id, accounts, evidence)
             }
-            EscrowInstruction::OpenBox => {
-                Self::process_open_box(program_id, accounts)
+            EscrowInstruction::RotateBoxId { new_id } => {
+                Self::process_rotate_box_id(program_id, accounts, new_id)
             }
-            EscrowInstruction::SweepBox => {
-                Self::process_sweep_box(program_id, accounts)
+            EscrowInstruction::RotateBoxIdToken { new_id } => {
+                Self::process_rotate_box_id_token(program_id, accounts, new_id)
             }
-            EscrowInstruction::CreateBoxToken { id, deadline_days, amount } => {
-                Self::process_create_box_token(program_id, accounts, id, deadline_days, amount)
+            EscrowInstruction::RecoverBox { new_id, payout } => {
+                Self::process_recover_box(program_id, accounts, new_id, payout)
             }
-            EscrowInstruction::OpenBoxToken => {
-                Self::process_open_box_token(program_id, accounts)
+            EscrowInstruction::RecoverBoxToken { new_id, payout } => {
+                Self::process_recover_box_token(program_id, accounts, new_id, payout)
             }
-            EscrowInstruction::SweepBoxToken => {
-                Self::process_sweep_box_token(program_id, accounts)
+            EscrowInstruction::SetBoxMeta { fields } => {
+                Self::process_set_box_meta(program_id, accounts, fields)
+            }
+            EscrowInstruction::CloseBoxMeta => {
+                Self::process_close_box_meta(program_id, accounts)
+            }
+            EscrowInstruction::ClaimReferralRewards => {
+                Self::process_claim_referral_rewards(program_id, accounts)
+            }
+            EscrowInstruction::MigrateAccount => {
+                Self::process_migrate_account(program_id, accounts)
+            }
+            EscrowInstruction::CommitFinish { proof_hash } => {
+                Self::process_commit_finish(program_id, accounts, proof_hash)
+            }
+            EscrowInstruction::CommitFinishToken { proof_hash } => {
+                Self::process_commit_finish_token(program_id, accounts, proof_hash)
+            }
+            EscrowInstruction::SetTimer { running } => {
+                Self::process_set_timer(program_id, accounts, running)
+            }
+            EscrowInstruction::SetTimerToken { running } => {
+                Self::process_set_timer_token(program_id, accounts, running)
             }
         }
     }
@@ -60,6 +211,7 @@ impl Processor {
         let authority = next_account_info(account_info_iter)?;
         let program_state_account = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
 
         if !authority.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
@@ -78,49 +230,156 @@ impl Processor {
         let space = ProgramState::LEN;
         let lamports = rent.minimum_balance(space);
 
-        invoke_signed(
-            &system_instruction::create_account(
-                authority.key,
-                program_state_account.key,
-                lamports,
-                space as u64,
-                program_id,
-            ),
-            &[authority.clone(), program_state_account.clone(), system_program.clone()],
-            &[&[b"program_state", &[bump]]],
+        Self::create_pda_account(
+            authority,
+            program_state_account,
+            system_program,
+            lamports,
+            space,
+            program_id,
+            &[b"program_state", &[bump]],
         )?;
 
-        let program_state = ProgramState {
-            authority: *authority.key,
-        };
+        let (treasury_pda, treasury_bump) = Pubkey::find_program_address(
+            &[b"treasury"],
+            program_id,
+        );
+
+        if treasury_pda != *treasury_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        // Program-owned with no data, so the program can move its lamports on withdrawal
+        Self::create_pda_account(
+            authority,
+            treasury_account,
+            system_program,
+            rent.minimum_balance(0),
+            0,
+            program_id,
+            &[b"treasury", &[treasury_bump]],
+        )?;
+
+        let program_state = ProgramState::new(*authority.key, treasury_pda);
         program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
 
+        EscrowEvent::AuthorityChanged(AuthorityChanged {
+            program_state: program_state_pda,
+            old_authority: Pubkey::default(),
+            new_authority: *authority.key,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
         Ok(())
     }
 
     fn process_create_box(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        id: Pubkey,
-        deadline_days: u16,
-        amount: u64,
+        entry: BoxEntry,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let sender = next_account_info(account_info_iter)?;
         let box_account = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if !sender.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if program_state.paused_create {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut profile = Self::load_or_create_profile(program_id, sender, sender.key, profile_account, system_program)?;
+        Self::set_referrer(&mut profile, &entry.options)?;
+
+        Self::create_box(program_id, sender, box_account, system_program, &program_state, &entry)?;
+        Self::create_box_meta(program_id, sender, box_account, system_program, &entry, account_info_iter)?;
+
+        profile.record_created(entry.amount)?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        program_state.record_box_created(entry.amount)?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    fn process_create_boxes(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        entries: Vec<BoxEntry>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let sender = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
 
         if !sender.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
+        if entries.is_empty() {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        if entries.len() > MAX_BATCH_BOXES {
+            return Err(EscrowError::TooManyBoxes.into());
+        }
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if program_state.paused_create {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut profile = Self::load_or_create_profile(program_id, sender, sender.key, profile_account, system_program)?;
+        for entry in &entries {
+            Self::set_referrer(&mut profile, &entry.options)?;
+        }
+
+        for entry in entries {
+            let box_account = next_account_info(account_info_iter)?;
+            Self::create_box(program_id, sender, box_account, system_program, &program_state, &entry)?;
+            Self::create_box_meta(program_id, sender, box_account, system_program, &entry, account_info_iter)?;
+            profile.record_created(entry.amount)?;
+            program_state.record_box_created(entry.amount)?;
+        }
+
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    /// Creates and funds a single SOL box; shared by `CreateBox` and `CreateBoxes`
+    fn create_box<'a>(
+        program_id: &Pubkey,
+        sender: &AccountInfo<'a>,
+        box_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        program_state: &ProgramState,
+        entry: &BoxEntry,
+    ) -> ProgramResult {
+        let BoxEntry { id, deadline_days, amount, .. } = *entry;
+
         if deadline_days == 0 || deadline_days > 365 {
             return Err(EscrowError::BadDeadline.into());
         }
 
-        if amount == 0 {
-            return Err(EscrowError::NoSol.into());
-        }
+        program_state.config.sol_limits.check(amount)?;
+
+        let arbiter = Self::box_arbiter(sender.key, &entry.options)?;
+        let guardians = GuardianSet::new(&entry.options.guardians, entry.options.guardian_threshold)?;
+        let book = Self::box_book(&entry.options)?;
+        let reading_mode = Self::box_reading_mode(&entry.options)?;
 
         let (box_pda, bump) = Pubkey::find_program_address(
             &[b"box", sender.key.as_ref(), id.as_ref()],
@@ -132,32 +391,41 @@ impl Processor {
         }
 
         let clock = Clock::get()?;
-        let final_deadline = clock
-            .unix_timestamp
-            .checked_add((deadline_days as i64).checked_mul(86400).unwrap())
-            .unwrap();
+        let final_deadline = entry.options.deadline_kind.deadline_after(&clock, deadline_days);
 
         let rent = Rent::get()?;
         let space = Box::LEN;
         let lamports = rent.minimum_balance(space);
 
-        invoke_signed(
-            &system_instruction::create_account(
-                sender.key,
-                box_account.key,
-                lamports,
-                space as u64,
-                program_id,
-            ),
-            &[sender.clone(), box_account.clone(), system_program.clone()],
-            &[&[b"box", sender.key.as_ref(), id.as_ref(), &[bump]]],
+        Self::create_pda_account(
+            sender,
+            box_account,
+            system_program,
+            lamports,
+            space,
+            program_id,
+            &[b"box", sender.key.as_ref(), id.as_ref(), &[bump]],
         )?;
 
         let escrow_box = Box {
+            kind: AccountKind::Box,
+            version: Box::VERSION,
             sender: *sender.key,
             id,
             deadline: final_deadline,
             amount,
+            pause_offset: program_state.open_pause_elapsed(clock.unix_timestamp),
+            terms: program_state.config.terms,
+            arbiter,
+            sweep_initiated_at: 0,
+            rotated_id: Pubkey::default(),
+            guardians,
+            recovery: Recovery::default(),
+            book,
+            reading_mode,
+            deadline_kind: entry.options.deadline_kind,
+            finish: FinishCommitment::default(),
+            timer: ReadingTimer::default(),
         };
         escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
 
@@ -168,100 +436,220 @@ impl Processor {
             &[],
         )?;
 
+        EscrowEvent::BoxCreated(BoxCreated {
+            box_pda,
+            sender: *sender.key,
+            id,
+            content_hash: book.content_hash,
+            amount,
+            deadline: final_deadline,
+            deadline_kind: entry.options.deadline_kind,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
         Ok(())
     }
 
     fn process_open_box(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
+        proof: ReadingProof,
+        mint_badge: bool,
+        relayer_fee: u64,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let box_account = next_account_info(account_info_iter)?;
         let recipient = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let id_signer = next_account_info(account_info_iter)?;
 
         if box_account.owner != program_id {
             return Err(ProgramError::InvalidAccountOwner);
         }
 
-        let mut escrow_box = Box::try_from_slice(&box_account.data.borrow())?;
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if program_state.paused_open {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
 
         if escrow_box.deadline == 0 {
             return Err(EscrowError::UnknownId.into());
         }
 
         let clock = Clock::get()?;
-        if clock.unix_timestamp >= escrow_box.deadline {
-            return Err(EscrowError::TooLate.into());
+        Self::check_id_signer(&escrow_box.id_key(clock.unix_timestamp), id_signer)?;
+        let effective_deadline = escrow_box.effective_deadline(&program_state, &clock);
+        Self::check_open_window(
+            effective_deadline,
+            escrow_box.terms.sweepable_at(effective_deadline, escrow_box.deadline_kind),
+            escrow_box.now(&clock),
+            &escrow_box.finish,
+            &proof,
+        )?;
+        Self::check_reading_proof(&escrow_box.reading_mode, &escrow_box.timer, &proof)?;
+
+        let badge = if mint_badge {
+            Some(Self::badge_accounts(account_info_iter)?)
+        } else {
+            None
+        };
+
+        if relayer_fee > escrow_box.terms.max_relayer_fee(escrow_box.amount) {
+            return Err(EscrowError::RelayerFeeTooHigh.into());
+        }
+
+        let relayer = if relayer_fee > 0 {
+            Some(next_account_info(account_info_iter)?)
+        } else {
+            None
+        };
+
+        if let Some(payout) = escrow_box.recovered_payout(clock.unix_timestamp) {
+            if *recipient.key != payout {
+                return Err(EscrowError::Unauthorized.into());
+            }
         }
 
         let amount = escrow_box.amount;
 
-        // Transfer SOL to recipient
+        let deadline = escrow_box.deadline;
+
+        // Transfer SOL to recipient, less the relayer's fee
         **box_account.try_borrow_mut_lamports()? -= amount;
-        **recipient.try_borrow_mut_lamports()? += amount;
+        **recipient.try_borrow_mut_lamports()? += amount - relayer_fee;
+
+        if let Some(relayer) = relayer {
+            **relayer.try_borrow_mut_lamports()? += relayer_fee;
+
+            EscrowEvent::RelayerPaid(RelayerPaid {
+                box_pda: *box_account.key,
+                relayer: *relayer.key,
+                amount: relayer_fee,
+                timestamp: clock.unix_timestamp,
+            })
+            .emit()?;
+        }
 
         // Mark box as closed
         escrow_box.deadline = 0;
         escrow_box.amount = 0;
         escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
 
+        let mut profile = Self::load_profile(program_id, &escrow_box.sender, profile_account)?;
+        profile.record_completed()?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        program_state.record_box_opened(amount)?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        if let Some(badge) = badge {
+            let owner = escrow_box.recovered_payout(clock.unix_timestamp).unwrap_or(escrow_box.sender);
+            Self::mint_badge(program_id, &badge, box_account.key, &owner, &escrow_box.book, clock.unix_timestamp)?;
+        }
+
+        EscrowEvent::BoxOpened(BoxOpened {
+            box_pda: *box_account.key,
+            sender: escrow_box.sender,
+            recipient: *recipient.key,
+            amount,
+            deadline,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
         Ok(())
     }
 
-    fn process_sweep_box(
+    fn process_finalize_sweep(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let program_state_account = next_account_info(account_info_iter)?;
         let box_account = next_account_info(account_info_iter)?;
-        let authority = next_account_info(account_info_iter)?;
-
-        if program_state_account.owner != program_id {
-            return Err(ProgramError::InvalidAccountOwner);
-        }
+        let beneficiary = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let keeper = next_account_info(account_info_iter)?;
 
         if box_account.owner != program_id {
             return Err(ProgramError::InvalidAccountOwner);
         }
 
-        let program_state = ProgramState::try_from_slice(&program_state_account.data.borrow())?;
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
 
-        if *authority.key != program_state.authority {
-            return Err(EscrowError::Unauthorized.into());
+        if program_state.paused_sweep {
+            return Err(EscrowError::Paused.into());
         }
 
-        let mut escrow_box = Box::try_from_slice(&box_account.data.borrow())?;
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
 
         if escrow_box.deadline == 0 {
             return Err(EscrowError::UnknownId.into());
         }
 
-        let clock = Clock::get()?;
-        if clock.unix_timestamp < escrow_box.deadline {
-            return Err(EscrowError::NotExpired.into());
+        // Forfeits go to the beneficiary the box was created under
+        if *beneficiary.key != escrow_box.terms.beneficiary {
+            return Err(EscrowError::Unauthorized.into());
         }
 
-        let amount = escrow_box.amount;
+        let clock = Clock::get()?;
+        Self::check_finalizable(&escrow_box.terms, escrow_box.sweep_initiated_at, clock.unix_timestamp)?;
 
-        // Transfer SOL to authority
-        **box_account.try_borrow_mut_lamports()? -= amount;
-        **authority.try_borrow_mut_lamports()? += amount;
+        let forfeited = escrow_box.amount;
+        let bounty = escrow_box.terms.keeper_bounty(forfeited);
+
+        let mut profile = Self::load_profile(program_id, &escrow_box.sender, profile_account)?;
+        let referral = match Self::next_referral_account(&profile, account_info_iter)? {
+            Some(referral_account) => {
+                let share = Self::forfeit_referral_share(program_id, &program_state, beneficiary.key, forfeited - bounty);
+                Self::accrue_referral(program_id, box_account, referral_account, &profile, share)?
+            }
+            None => 0,
+        };
+        let amount = forfeited - bounty - referral;
+
+        let deadline = escrow_box.deadline;
+
+        // Transfer SOL to beneficiary, less the keeper bounty and the referrer's share
+        **box_account.try_borrow_mut_lamports()? -= forfeited - referral;
+        **beneficiary.try_borrow_mut_lamports()? += amount;
+        **keeper.try_borrow_mut_lamports()? += bounty;
 
         // Mark box as closed
         escrow_box.deadline = 0;
         escrow_box.amount = 0;
         escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
 
+        profile.record_forfeited(forfeited)?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        program_state.record_box_swept(forfeited)?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::BoxSwept(BoxSwept {
+            box_pda: *box_account.key,
+            sender: escrow_box.sender,
+            destination: *beneficiary.key,
+            keeper: *keeper.key,
+            amount,
+            bounty,
+            deadline,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
         Ok(())
     }
 
     fn process_create_box_token(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        id: Pubkey,
-        deadline_days: u16,
-        amount: u64,
+        entry: BoxEntry,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let sender = next_account_info(account_info_iter)?;
@@ -273,18 +661,162 @@ impl Processor {
         let token_program = next_account_info(account_info_iter)?;
         let associated_token_program = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let mint_config_account = next_account_info(account_info_iter)?;
+
+        if !sender.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if program_state.paused_create {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mint_config = Self::load_mint_config(program_id, mint, mint_config_account)?;
+        let mut profile = Self::load_or_create_profile(program_id, sender, sender.key, profile_account, system_program)?;
+        Self::set_referrer(&mut profile, &entry.options)?;
+
+        let setup = TokenBoxSetup {
+            sender,
+            sender_token_account,
+            mint,
+            token_program,
+            associated_token_program,
+            system_program,
+            stake_limits: mint_config.limits,
+        };
+
+        Self::create_token_box(
+            program_id,
+            &setup,
+            token_box_account,
+            vault_ata,
+            vault_authority_info,
+            &program_state,
+            &entry,
+        )?;
+        Self::create_box_meta(program_id, sender, token_box_account, system_program, &entry, account_info_iter)?;
+
+        profile.record_created(0)?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        program_state.record_token_box_created()?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    fn process_create_boxes_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        entries: Vec<BoxEntry>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let sender = next_account_info(account_info_iter)?;
+        let sender_token_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let mint_config_account = next_account_info(account_info_iter)?;
 
         if !sender.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
+        if entries.is_empty() {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        if entries.len() > MAX_BATCH_BOXES {
+            return Err(EscrowError::TooManyBoxes.into());
+        }
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if program_state.paused_create {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mint_config = Self::load_mint_config(program_id, mint, mint_config_account)?;
+        let mut profile = Self::load_or_create_profile(program_id, sender, sender.key, profile_account, system_program)?;
+        for entry in &entries {
+            Self::set_referrer(&mut profile, &entry.options)?;
+        }
+
+        // Sender, mint, limits and programs are shared by every box of the batch
+        let setup = TokenBoxSetup {
+            sender,
+            sender_token_account,
+            mint,
+            token_program,
+            associated_token_program,
+            system_program,
+            stake_limits: mint_config.limits,
+        };
+
+        for entry in entries {
+            let token_box_account = next_account_info(account_info_iter)?;
+            let vault_ata = next_account_info(account_info_iter)?;
+            let vault_authority_info = next_account_info(account_info_iter)?;
+            Self::create_token_box(
+                program_id,
+                &setup,
+                token_box_account,
+                vault_ata,
+                vault_authority_info,
+                &program_state,
+                &entry,
+            )?;
+            Self::create_box_meta(program_id, sender, token_box_account, system_program, &entry, account_info_iter)?;
+            profile.record_created(0)?;
+            program_state.record_token_box_created()?;
+        }
+
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    /// Creates a single token box, its vault and moves the stake into it;
+    /// shared by `CreateBoxToken` and `CreateBoxesToken`
+    fn create_token_box<'a>(
+        program_id: &Pubkey,
+        setup: &TokenBoxSetup<'_, 'a>,
+        token_box_account: &AccountInfo<'a>,
+        vault_ata: &AccountInfo<'a>,
+        vault_authority_info: &AccountInfo<'a>,
+        program_state: &ProgramState,
+        entry: &BoxEntry,
+    ) -> ProgramResult {
+        let BoxEntry { id, deadline_days, amount, .. } = *entry;
+
+        let arbiter = Self::box_arbiter(setup.sender.key, &entry.options)?;
+        let guardians = GuardianSet::new(&entry.options.guardians, entry.options.guardian_threshold)?;
+        let book = Self::box_book(&entry.options)?;
+        let reading_mode = Self::box_reading_mode(&entry.options)?;
+
+        let TokenBoxSetup {
+            sender,
+            sender_token_account,
+            mint,
+            token_program,
+            associated_token_program,
+            system_program,
+            stake_limits,
+        } = *setup;
+
         if deadline_days == 0 || deadline_days > 365 {
             return Err(EscrowError::BadDeadline.into());
         }
 
-        if amount == 0 {
-            return Err(EscrowError::NoSol.into());
-        }
+        stake_limits.check(amount)?;
 
         // Derive TokenBox PDA
         let (token_box_pda, box_bump) = Pubkey::find_program_address(
@@ -314,35 +846,44 @@ impl Processor {
         }
 
         let clock = Clock::get()?;
-        let final_deadline = clock
-            .unix_timestamp
-            .checked_add((deadline_days as i64).checked_mul(86400).unwrap())
-            .unwrap();
+        let final_deadline = entry.options.deadline_kind.deadline_after(&clock, deadline_days);
 
         // Create TokenBox account
         let rent = Rent::get()?;
         let space = TokenBox::LEN;
         let lamports = rent.minimum_balance(space);
 
-        invoke_signed(
-            &system_instruction::create_account(
-                sender.key,
-                token_box_account.key,
-                lamports,
-                space as u64,
-                program_id,
-            ),
-            &[sender.clone(), token_box_account.clone(), system_program.clone()],
-            &[&[b"token_box", sender.key.as_ref(), id.as_ref(), &[box_bump]]],
+        Self::create_pda_account(
+            sender,
+            token_box_account,
+            system_program,
+            lamports,
+            space,
+            program_id,
+            &[b"token_box", sender.key.as_ref(), id.as_ref(), &[box_bump]],
         )?;
 
         // Save TokenBox state
         let token_box = TokenBox {
+            kind: AccountKind::TokenBox,
+            version: TokenBox::VERSION,
             sender: *sender.key,
             id,
             deadline: final_deadline,
             amount,
             mint: *mint.key,
+            pause_offset: program_state.open_pause_elapsed(clock.unix_timestamp),
+            terms: program_state.config.terms,
+            arbiter,
+            sweep_initiated_at: 0,
+            rotated_id: Pubkey::default(),
+            guardians,
+            recovery: Recovery::default(),
+            book,
+            reading_mode,
+            deadline_kind: entry.options.deadline_kind,
+            finish: FinishCommitment::default(),
+            timer: ReadingTimer::default(),
         };
         token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
 
@@ -385,12 +926,28 @@ impl Processor {
             ],
         )?;
 
+        EscrowEvent::TokenBoxCreated(TokenBoxCreated {
+            box_pda: token_box_pda,
+            sender: *sender.key,
+            id,
+            content_hash: book.content_hash,
+            mint: *mint.key,
+            amount,
+            deadline: final_deadline,
+            deadline_kind: entry.options.deadline_kind,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
         Ok(())
     }
 
     fn process_open_box_token(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
+        proof: ReadingProof,
+        mint_badge: bool,
+        relayer_fee: u64,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let token_box_account = next_account_info(account_info_iter)?;
@@ -399,20 +956,58 @@ impl Processor {
         let sender = next_account_info(account_info_iter)?;
         let vault_authority_info = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let id_signer = next_account_info(account_info_iter)?;
 
         if token_box_account.owner != program_id {
             return Err(ProgramError::InvalidAccountOwner);
         }
 
-        let mut token_box = TokenBox::try_from_slice(&token_box_account.data.borrow())?;
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if program_state.paused_open {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
 
         if token_box.deadline == 0 {
             return Err(EscrowError::UnknownId.into());
         }
 
         let clock = Clock::get()?;
-        if clock.unix_timestamp >= token_box.deadline {
-            return Err(EscrowError::TooLate.into());
+        Self::check_id_signer(&token_box.id_key(clock.unix_timestamp), id_signer)?;
+        let effective_deadline = token_box.effective_deadline(&program_state, &clock);
+        Self::check_open_window(
+            effective_deadline,
+            token_box.terms.sweepable_at(effective_deadline, token_box.deadline_kind),
+            token_box.now(&clock),
+            &token_box.finish,
+            &proof,
+        )?;
+        Self::check_reading_proof(&token_box.reading_mode, &token_box.timer, &proof)?;
+
+        let badge = if mint_badge {
+            Some(Self::badge_accounts(account_info_iter)?)
+        } else {
+            None
+        };
+
+        if relayer_fee > token_box.terms.max_relayer_fee(token_box.amount) {
+            return Err(EscrowError::RelayerFeeTooHigh.into());
+        }
+
+        let relayer_token_account = if relayer_fee > 0 {
+            Some(next_account_info(account_info_iter)?)
+        } else {
+            None
+        };
+
+        if let Some(payout) = token_box.recovered_payout(clock.unix_timestamp) {
+            if get_associated_token_address(&payout, &token_box.mint) != *recipient_token_account.key {
+                return Err(EscrowError::InvalidTokenAccount.into());
+            }
         }
 
         // Derive vault authority PDA
@@ -428,15 +1023,43 @@ impl Processor {
 
         let amount = token_box.amount;
 
-        // Transfer tokens from vault to recipient
-        invoke_signed(
-            &token_instruction::transfer(
-                token_program.key,
+        if let Some(relayer_token_account) = relayer_token_account {
+            invoke_signed(
+                &token_instruction::transfer(
+                    token_program.key,
+                    vault_ata.key,
+                    relayer_token_account.key,
+                    &vault_authority,
+                    &[],
+                    relayer_fee,
+                )?,
+                &[
+                    vault_ata.clone(),
+                    relayer_token_account.clone(),
+                    vault_authority_info.clone(),
+                    token_program.clone(),
+                ],
+                &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
+            )?;
+
+            EscrowEvent::RelayerPaid(RelayerPaid {
+                box_pda: *token_box_account.key,
+                relayer: *relayer_token_account.key,
+                amount: relayer_fee,
+                timestamp: clock.unix_timestamp,
+            })
+            .emit()?;
+        }
+
+        // Transfer tokens from vault to recipient, less the relayer's fee
+        invoke_signed(
+            &token_instruction::transfer(
+                token_program.key,
                 vault_ata.key,
                 recipient_token_account.key,
                 &vault_authority,
                 &[],
-                amount,
+                amount - relayer_fee,
             )?,
             &[
                 vault_ata.clone(),
@@ -465,15 +1088,40 @@ impl Processor {
             &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
         )?;
 
+        let deadline = token_box.deadline;
+
         // Mark box as closed
         token_box.deadline = 0;
         token_box.amount = 0;
         token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
 
+        let mut profile = Self::load_profile(program_id, &token_box.sender, profile_account)?;
+        profile.record_completed()?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        program_state.record_token_box_opened()?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        if let Some(badge) = badge {
+            let owner = token_box.recovered_payout(clock.unix_timestamp).unwrap_or(token_box.sender);
+            Self::mint_badge(program_id, &badge, token_box_account.key, &owner, &token_box.book, clock.unix_timestamp)?;
+        }
+
+        EscrowEvent::TokenBoxOpened(TokenBoxOpened {
+            box_pda: *token_box_account.key,
+            sender: token_box.sender,
+            recipient: *recipient_token_account.key,
+            mint: token_box.mint,
+            amount,
+            deadline,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
         Ok(())
     }
 
-    fn process_sweep_box_token(
+    fn process_finalize_sweep_token(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
@@ -481,38 +1129,40 @@ impl Processor {
         let program_state_account = next_account_info(account_info_iter)?;
         let token_box_account = next_account_info(account_info_iter)?;
         let vault_ata = next_account_info(account_info_iter)?;
-        let authority_token_account = next_account_info(account_info_iter)?;
-        let authority = next_account_info(account_info_iter)?;
+        let beneficiary_token_account = next_account_info(account_info_iter)?;
+        let sender = next_account_info(account_info_iter)?;
         let vault_authority_info = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
-
-        if program_state_account.owner != program_id {
-            return Err(ProgramError::InvalidAccountOwner);
-        }
+        let profile_account = next_account_info(account_info_iter)?;
+        let keeper_token_account = next_account_info(account_info_iter)?;
 
         if token_box_account.owner != program_id {
             return Err(ProgramError::InvalidAccountOwner);
         }
 
-        let program_state = ProgramState::try_from_slice(&program_state_account.data.borrow())?;
-
-        if *authority.key != program_state.authority {
-            return Err(EscrowError::Unauthorized.into());
-        }
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
 
-        if !authority.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
+        if program_state.paused_sweep {
+            return Err(EscrowError::Paused.into());
         }
 
-        let mut token_box = TokenBox::try_from_slice(&token_box_account.data.borrow())?;
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
 
         if token_box.deadline == 0 {
             return Err(EscrowError::UnknownId.into());
         }
 
+        if *sender.key != token_box.sender {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
         let clock = Clock::get()?;
-        if clock.unix_timestamp < token_box.deadline {
-            return Err(EscrowError::NotExpired.into());
+        Self::check_finalizable(&token_box.terms, token_box.sweep_initiated_at, clock.unix_timestamp)?;
+
+        // Forfeits go to the beneficiary the box was created under
+        let expected_beneficiary_ata = get_associated_token_address(&token_box.terms.beneficiary, &token_box.mint);
+        if expected_beneficiary_ata != *beneficiary_token_account.key {
+            return Err(EscrowError::InvalidTokenAccount.into());
         }
 
         // Derive vault authority PDA
@@ -526,51 +1176,3772 @@ impl Processor {
             return Err(ProgramError::InvalidSeeds);
         }
 
-        let amount = token_box.amount;
+        let bounty = token_box.terms.keeper_bounty(token_box.amount);
+
+        let mut profile = Self::load_profile(program_id, &token_box.sender, profile_account)?;
+        let referral = match Self::next_referral_account(&profile, account_info_iter)? {
+            Some(referral_token_account) => {
+                let share = Self::forfeit_referral_share(
+                    program_id,
+                    &program_state,
+                    &token_box.terms.beneficiary,
+                    token_box.amount - bounty,
+                );
+                Self::pay_token_referral(
+                    &VaultAccounts {
+                        token_box: token_box_account,
+                        vault_ata,
+                        vault_authority: vault_authority_info,
+                        vault_bump,
+                        token_program,
+                    },
+                    &token_box,
+                    referral_token_account,
+                    &profile,
+                    share,
+                )?
+            }
+            None => 0,
+        };
+        let amount = token_box.amount - bounty - referral;
+
+        // Pay the keeper bounty from the vault
+        if bounty > 0 {
+            invoke_signed(
+                &token_instruction::transfer(
+                    token_program.key,
+                    vault_ata.key,
+                    keeper_token_account.key,
+                    &vault_authority,
+                    &[],
+                    bounty,
+                )?,
+                &[
+                    vault_ata.clone(),
+                    keeper_token_account.clone(),
+                    vault_authority_info.clone(),
+                    token_program.clone(),
+                ],
+                &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
+            )?;
+        }
 
-        // Transfer tokens from vault to authority
+        // Transfer the rest from vault to beneficiary
         invoke_signed(
             &token_instruction::transfer(
                 token_program.key,
                 vault_ata.key,
-                authority_token_account.key,
+                beneficiary_token_account.key,
                 &vault_authority,
                 &[],
                 amount,
             )?,
             &[
                 vault_ata.clone(),
-                authority_token_account.clone(),
+                beneficiary_token_account.clone(),
                 vault_authority_info.clone(),
                 token_program.clone(),
             ],
             &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
         )?;
 
-        // Close vault ATA and return rent to authority
+        // Close vault ATA and return rent to sender, who paid it
         invoke_signed(
             &token_instruction::close_account(
                 token_program.key,
                 vault_ata.key,
-                authority.key,
+                sender.key,
                 &vault_authority,
                 &[],
             )?,
             &[
                 vault_ata.clone(),
-                authority.clone(),
+                sender.clone(),
                 vault_authority_info.clone(),
                 token_program.clone(),
             ],
             &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
         )?;
 
+        let deadline = token_box.deadline;
+
         // Mark box as closed
         token_box.deadline = 0;
         token_box.amount = 0;
         token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
 
+        profile.record_forfeited(0)?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        program_state.record_token_box_swept()?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::TokenBoxSwept(TokenBoxSwept {
+            box_pda: *token_box_account.key,
+            sender: token_box.sender,
+            destination: *beneficiary_token_account.key,
+            keeper: *keeper_token_account.key,
+            mint: token_box.mint,
+            amount,
+            bounty,
+            deadline,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
         Ok(())
     }
-}
 
+    fn process_set_paused(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        create: bool,
+        open: bool,
+        sweep: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        Self::check_authority(&program_state, authority, account_info_iter.as_slice())?;
+
+        let clock = Clock::get()?;
+
+        // Keep the pause clock running only while opening is paused
+        if open && !program_state.paused_open {
+            program_state.open_paused_at = clock.unix_timestamp;
+        } else if !open && program_state.paused_open {
+            program_state.open_paused_total = program_state.open_pause_elapsed(clock.unix_timestamp);
+            program_state.open_paused_at = 0;
+        }
+
+        program_state.paused_create = create;
+        program_state.paused_open = open;
+        program_state.paused_sweep = sweep;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::PauseChanged(PauseChanged {
+            authority: *authority.key,
+            create,
+            open,
+            sweep,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_add_mint(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_stake: u64,
+        max_stake: u64,
+        allow_freeze_authority: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let mint_config_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        Self::check_authority(&program_state, authority, account_info_iter.as_slice())?;
+
+        if *mint.owner != spl_token::id() {
+            return Err(EscrowError::InvalidMint.into());
+        }
+
+        let mint_state = spl_token::state::Mint::unpack(&mint.data.borrow())
+            .map_err(|_| EscrowError::InvalidMint)?;
+
+        if mint_state.freeze_authority.is_some() && !allow_freeze_authority {
+            return Err(EscrowError::FreezableMint.into());
+        }
+
+        let limits = StakeLimits { min: min_stake, max: max_stake };
+        if !limits.is_valid() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let (mint_config_pda, bump) = Pubkey::find_program_address(
+            &[b"mint_config", mint.key.as_ref()],
+            program_id,
+        );
+
+        if mint_config_pda != *mint_config_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        if mint_config_account.data_is_empty() {
+            let rent = Rent::get()?;
+            let space = MintConfig::LEN;
+            let lamports = rent.minimum_balance(space);
+
+            Self::create_pda_account(
+                authority,
+                mint_config_account,
+                system_program,
+                lamports,
+                space,
+                program_id,
+                &[b"mint_config", mint.key.as_ref(), &[bump]],
+            )?;
+        } else if mint_config_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mint_config = MintConfig::new(*mint.key, mint_state.decimals, limits, allow_freeze_authority);
+        mint_config.serialize(&mut &mut mint_config_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::MintConfigured(MintConfigured {
+            mint: *mint.key,
+            decimals: mint_state.decimals,
+            min_stake,
+            max_stake,
+            allow_freeze_authority,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_remove_mint(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let mint_config_account = next_account_info(account_info_iter)?;
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        Self::check_authority(&program_state, authority, account_info_iter.as_slice())?;
+
+        if mint_config_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mint_config = MintConfig::unpack(&mint_config_account.data.borrow())?;
+
+        let (mint_config_pda, _) = Pubkey::find_program_address(
+            &[b"mint_config", mint_config.mint.as_ref()],
+            program_id,
+        );
+
+        if mint_config_pda != *mint_config_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        // Close the MintConfig account and return its rent to the authority
+        Self::close_account(mint_config_account, authority)?;
+
+        EscrowEvent::MintRemoved(MintRemoved {
+            mint: mint_config.mint,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_queue_config(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        config: ProtocolConfig,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority = next_account_info(account_info_iter)?;
+        let payer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let pending_config_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        Self::check_authority(&program_state, authority, account_info_iter.as_slice())?;
+
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        config.validate()?;
+
+        let (pending_config_pda, bump) = Pubkey::find_program_address(
+            &[b"pending_config"],
+            program_id,
+        );
+
+        if pending_config_pda != *pending_config_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        // Only one change may be queued at a time; cancel it to queue another
+        if !pending_config_account.data_is_empty() {
+            return Err(EscrowError::ConfigPending.into());
+        }
+
+        let rent = Rent::get()?;
+        let space = PendingConfig::LEN;
+        let lamports = rent.minimum_balance(space);
+
+        Self::create_pda_account(
+            payer,
+            pending_config_account,
+            system_program,
+            lamports,
+            space,
+            program_id,
+            &[b"pending_config", &[bump]],
+        )?;
+
+        let clock = Clock::get()?;
+        let execute_after = clock
+            .unix_timestamp
+            .checked_add(CONFIG_TIMELOCK)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        let pending_config = PendingConfig::new(config.clone(), execute_after, *payer.key);
+        pending_config.serialize(&mut &mut pending_config_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::ConfigQueued(ConfigQueued {
+            config,
+            execute_after,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_execute_config(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let program_state_account = next_account_info(account_info_iter)?;
+        let pending_config_account = next_account_info(account_info_iter)?;
+        let payer = next_account_info(account_info_iter)?;
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+        let pending_config = Self::load_pending_config(program_id, pending_config_account)?;
+
+        let clock = Clock::get()?;
+        if clock.unix_timestamp < pending_config.execute_after {
+            return Err(EscrowError::TimelockActive.into());
+        }
+
+        if *payer.key != pending_config.payer {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let old_authority = program_state.config.authority;
+        program_state.config = pending_config.config.clone();
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        Self::close_account(pending_config_account, payer)?;
+
+        EscrowEvent::ConfigExecuted(ConfigExecuted {
+            config: pending_config.config,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        if program_state.config.authority != old_authority {
+            EscrowEvent::AuthorityChanged(AuthorityChanged {
+                program_state: *program_state_account.key,
+                old_authority,
+                new_authority: program_state.config.authority,
+                timestamp: clock.unix_timestamp,
+            })
+            .emit()?;
+        }
+
+        Ok(())
+    }
+
+    fn process_cancel_config(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let pending_config_account = next_account_info(account_info_iter)?;
+        let payer = next_account_info(account_info_iter)?;
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        Self::check_authority(&program_state, authority, account_info_iter.as_slice())?;
+
+        let pending_config = Self::load_pending_config(program_id, pending_config_account)?;
+
+        if *payer.key != pending_config.payer {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        Self::close_account(pending_config_account, payer)?;
+
+        EscrowEvent::ConfigCancelled(ConfigCancelled {
+            config: pending_config.config,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_withdraw_treasury(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
+        let destination = next_account_info(account_info_iter)?;
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        Self::check_authority(&program_state, authority, account_info_iter.as_slice())?;
+
+        Self::treasury_bump(program_id, treasury_account)?;
+
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        let rent = Rent::get()?;
+        let available = treasury_account
+            .lamports()
+            .saturating_sub(rent.minimum_balance(0));
+        if amount > available {
+            return Err(EscrowError::InsufficientTreasury.into());
+        }
+
+        **treasury_account.try_borrow_mut_lamports()? -= amount;
+        **destination.try_borrow_mut_lamports()? += amount;
+
+        EscrowEvent::TreasuryWithdrawn(TreasuryWithdrawn {
+            destination: *destination.key,
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_withdraw_treasury_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let authority = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
+        let treasury_token_account = next_account_info(account_info_iter)?;
+        let destination_token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        Self::check_authority(&program_state, authority, account_info_iter.as_slice())?;
+
+        let treasury_bump = Self::treasury_bump(program_id, treasury_account)?;
+
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        // The treasury PDA signs this transfer, so it must only reach SPL Token
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        if *treasury_token_account.owner != spl_token::id() {
+            return Err(EscrowError::InvalidTokenAccount.into());
+        }
+
+        let treasury_tokens = spl_token::state::Account::unpack(&treasury_token_account.data.borrow())
+            .map_err(|_| EscrowError::InvalidTokenAccount)?;
+
+        if treasury_tokens.owner != *treasury_account.key {
+            return Err(EscrowError::InvalidTokenAccount.into());
+        }
+
+        if amount > treasury_tokens.amount {
+            return Err(EscrowError::InsufficientTreasury.into());
+        }
+
+        invoke_signed(
+            &token_instruction::transfer(
+                token_program.key,
+                treasury_token_account.key,
+                destination_token_account.key,
+                treasury_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                treasury_token_account.clone(),
+                destination_token_account.clone(),
+                treasury_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[b"treasury", &[treasury_bump]]],
+        )?;
+
+        EscrowEvent::TreasuryTokenWithdrawn(TreasuryTokenWithdrawn {
+            destination: *destination_token_account.key,
+            mint: treasury_tokens.mint,
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Checks the account is the program's treasury PDA and returns its bump
+    fn treasury_bump(
+        program_id: &Pubkey,
+        treasury_account: &AccountInfo,
+    ) -> Result<u8, ProgramError> {
+        if treasury_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let (treasury_pda, bump) = Pubkey::find_program_address(
+            &[b"treasury"],
+            program_id,
+        );
+
+        if treasury_pda != *treasury_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        Ok(bump)
+    }
+
+    /// Loads the queued config change, checking the account is the pending config PDA
+    fn load_pending_config(
+        program_id: &Pubkey,
+        pending_config_account: &AccountInfo,
+    ) -> Result<PendingConfig, ProgramError> {
+        if pending_config_account.owner != program_id {
+            return Err(EscrowError::NoConfigPending.into());
+        }
+
+        let (pending_config_pda, _) = Pubkey::find_program_address(
+            &[b"pending_config"],
+            program_id,
+        );
+
+        if pending_config_pda != *pending_config_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        PendingConfig::unpack(&pending_config_account.data.borrow())
+    }
+
+    /// Settles a SOL box on the arbiter's decision: back to the sender or forfeited to the beneficiary
+    fn process_arbiter_decision(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        release: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let box_account = next_account_info(account_info_iter)?;
+        let arbiter = next_account_info(account_info_iter)?;
+        let destination = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if (release && program_state.paused_open) || (!release && program_state.paused_sweep) {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
+
+        if escrow_box.deadline == 0 {
+            return Err(EscrowError::UnknownId.into());
+        }
+
+        if !arbiter.is_signer || *arbiter.key != escrow_box.arbiter {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        let clock = Clock::get()?;
+
+        let expected_destination = if release {
+            escrow_box.recovered_payout(clock.unix_timestamp).unwrap_or(escrow_box.sender)
+        } else {
+            escrow_box.terms.beneficiary
+        };
+        if *destination.key != expected_destination {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        if escrow_box.now(&clock) >= escrow_box.effective_deadline(&program_state, &clock) {
+            return Err(EscrowError::TooLate.into());
+        }
+
+        let amount = escrow_box.amount;
+
+        let mut profile = Self::load_profile(program_id, &escrow_box.sender, profile_account)?;
+        let referral = if release {
+            0
+        } else {
+            match Self::next_referral_account(&profile, account_info_iter)? {
+                Some(referral_account) => {
+                    let share = Self::forfeit_referral_share(program_id, &program_state, destination.key, amount);
+                    Self::accrue_referral(program_id, box_account, referral_account, &profile, share)?
+                }
+                None => 0,
+            }
+        };
+
+        let deadline = escrow_box.deadline;
+
+        **box_account.try_borrow_mut_lamports()? -= amount - referral;
+        **destination.try_borrow_mut_lamports()? += amount - referral;
+
+        // Mark box as closed
+        escrow_box.deadline = 0;
+        escrow_box.amount = 0;
+        escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+        if release {
+            profile.record_completed()?;
+            program_state.record_box_opened(amount)?;
+        } else {
+            profile.record_forfeited(amount)?;
+            program_state.record_box_swept(amount)?;
+        }
+
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        let event = if release {
+            EscrowEvent::BoxOpened(BoxOpened {
+                box_pda: *box_account.key,
+                sender: escrow_box.sender,
+                recipient: *destination.key,
+                amount,
+                deadline,
+                timestamp: clock.unix_timestamp,
+            })
+        } else {
+            EscrowEvent::BoxSwept(BoxSwept {
+                box_pda: *box_account.key,
+                sender: escrow_box.sender,
+                destination: *destination.key,
+                keeper: *arbiter.key,
+                amount,
+                bounty: 0,
+                deadline,
+                timestamp: clock.unix_timestamp,
+            })
+        };
+        event.emit()?;
+
+        Ok(())
+    }
+
+    /// Token variant of `process_arbiter_decision`
+    fn process_arbiter_decision_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        release: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let token_box_account = next_account_info(account_info_iter)?;
+        let arbiter = next_account_info(account_info_iter)?;
+        let vault_ata = next_account_info(account_info_iter)?;
+        let destination_token_account = next_account_info(account_info_iter)?;
+        let sender = next_account_info(account_info_iter)?;
+        let vault_authority_info = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if token_box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if (release && program_state.paused_open) || (!release && program_state.paused_sweep) {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
+
+        if token_box.deadline == 0 {
+            return Err(EscrowError::UnknownId.into());
+        }
+
+        if !arbiter.is_signer || *arbiter.key != token_box.arbiter {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        if *sender.key != token_box.sender {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        let clock = Clock::get()?;
+
+        let destination_owner = if release {
+            token_box.recovered_payout(clock.unix_timestamp).unwrap_or(token_box.sender)
+        } else {
+            token_box.terms.beneficiary
+        };
+        let expected_destination = get_associated_token_address(&destination_owner, &token_box.mint);
+        if expected_destination != *destination_token_account.key {
+            return Err(EscrowError::InvalidTokenAccount.into());
+        }
+
+        if token_box.now(&clock) >= token_box.effective_deadline(&program_state, &clock) {
+            return Err(EscrowError::TooLate.into());
+        }
+
+        // Derive vault authority PDA
+        let (vault_authority, vault_bump) = Pubkey::find_program_address(
+            &[b"vault", token_box_account.key.as_ref()],
+            program_id,
+        );
+
+        // Verify vault authority account matches derived PDA
+        if vault_authority != *vault_authority_info.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let mut profile = Self::load_profile(program_id, &token_box.sender, profile_account)?;
+        let referral = if release {
+            0
+        } else {
+            match Self::next_referral_account(&profile, account_info_iter)? {
+                Some(referral_token_account) => {
+                    let share = Self::forfeit_referral_share(
+                        program_id,
+                        &program_state,
+                        &token_box.terms.beneficiary,
+                        token_box.amount,
+                    );
+                    Self::pay_token_referral(
+                        &VaultAccounts {
+                            token_box: token_box_account,
+                            vault_ata,
+                            vault_authority: vault_authority_info,
+                            vault_bump,
+                            token_program,
+                        },
+                        &token_box,
+                        referral_token_account,
+                        &profile,
+                        share,
+                    )?
+                }
+                None => 0,
+            }
+        };
+        let amount = token_box.amount - referral;
+
+        // Transfer tokens from vault to destination
+        invoke_signed(
+            &token_instruction::transfer(
+                token_program.key,
+                vault_ata.key,
+                destination_token_account.key,
+                &vault_authority,
+                &[],
+                amount,
+            )?,
+            &[
+                vault_ata.clone(),
+                destination_token_account.clone(),
+                vault_authority_info.clone(),
+                token_program.clone(),
+            ],
+            &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
+        )?;
+
+        // Close vault ATA and return rent to sender
+        invoke_signed(
+            &token_instruction::close_account(
+                token_program.key,
+                vault_ata.key,
+                sender.key,
+                &vault_authority,
+                &[],
+            )?,
+            &[
+                vault_ata.clone(),
+                sender.clone(),
+                vault_authority_info.clone(),
+                token_program.clone(),
+            ],
+            &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
+        )?;
+
+        let deadline = token_box.deadline;
+
+        // Mark box as closed
+        token_box.deadline = 0;
+        token_box.amount = 0;
+        token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
+
+        if release {
+            profile.record_completed()?;
+            program_state.record_token_box_opened()?;
+        } else {
+            profile.record_forfeited(0)?;
+            program_state.record_token_box_swept()?;
+        }
+
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        let event = if release {
+            EscrowEvent::TokenBoxOpened(TokenBoxOpened {
+                box_pda: *token_box_account.key,
+                sender: token_box.sender,
+                recipient: *destination_token_account.key,
+                mint: token_box.mint,
+                amount,
+                deadline,
+                timestamp: clock.unix_timestamp,
+            })
+        } else {
+            EscrowEvent::TokenBoxSwept(TokenBoxSwept {
+                box_pda: *token_box_account.key,
+                sender: token_box.sender,
+                destination: *destination_token_account.key,
+                keeper: *arbiter.key,
+                mint: token_box.mint,
+                amount,
+                bounty: 0,
+                deadline,
+                timestamp: clock.unix_timestamp,
+            })
+        };
+        event.emit()?;
+
+        Ok(())
+    }
+
+    fn process_initiate_sweep(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let program_state_account = next_account_info(account_info_iter)?;
+        let box_account = next_account_info(account_info_iter)?;
+
+        if box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if program_state.paused_sweep {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
+
+        let clock = Clock::get()?;
+        let effective_deadline = escrow_box.effective_deadline(&program_state, &clock);
+        Self::check_sweep_initiable(
+            escrow_box.deadline,
+            escrow_box.sweep_initiated_at,
+            escrow_box.now(&clock),
+            escrow_box.terms.sweepable_at(effective_deadline, escrow_box.deadline_kind),
+        )?;
+
+        escrow_box.sweep_initiated_at = clock.unix_timestamp;
+        escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::SweepInitiated(SweepInitiated {
+            box_pda: *box_account.key,
+            sender: escrow_box.sender,
+            finalize_after: escrow_box.terms.finalizable_at(clock.unix_timestamp),
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_initiate_sweep_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let program_state_account = next_account_info(account_info_iter)?;
+        let token_box_account = next_account_info(account_info_iter)?;
+
+        if token_box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        if program_state.paused_sweep {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
+
+        let clock = Clock::get()?;
+        let effective_deadline = token_box.effective_deadline(&program_state, &clock);
+        Self::check_sweep_initiable(
+            token_box.deadline,
+            token_box.sweep_initiated_at,
+            token_box.now(&clock),
+            token_box.terms.sweepable_at(effective_deadline, token_box.deadline_kind),
+        )?;
+
+        token_box.sweep_initiated_at = clock.unix_timestamp;
+        token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::SweepInitiated(SweepInitiated {
+            box_pda: *token_box_account.key,
+            sender: token_box.sender,
+            finalize_after: token_box.terms.finalizable_at(clock.unix_timestamp),
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_appeal(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        evidence: AppealEvidence,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let box_account = next_account_info(account_info_iter)?;
+        let sender = next_account_info(account_info_iter)?;
+        let recipient = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
+
+        Self::check_box_sender(&escrow_box.sender, escrow_box.deadline, sender)?;
+
+        let clock = Clock::get()?;
+
+        // The stake goes where an open would send it
+        if *recipient.key != escrow_box.recovered_payout(clock.unix_timestamp).unwrap_or(escrow_box.sender) {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        Self::check_appeal(
+            &escrow_box.terms,
+            &escrow_box.reading_mode,
+            escrow_box.sweep_initiated_at,
+            clock.unix_timestamp,
+            &evidence,
+            account_info_iter.as_slice(),
+        )?;
+
+        let amount = escrow_box.amount;
+
+        let deadline = escrow_box.deadline;
+
+        // Transfer SOL back to the sender, or the recovery payout
+        **box_account.try_borrow_mut_lamports()? -= amount;
+        **recipient.try_borrow_mut_lamports()? += amount;
+
+        // Mark box as closed
+        escrow_box.deadline = 0;
+        escrow_box.amount = 0;
+        escrow_box.sweep_initiated_at = 0;
+        escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+        let mut profile = Self::load_profile(program_id, &escrow_box.sender, profile_account)?;
+        profile.record_completed()?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        program_state.record_box_opened(amount)?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::BoxOpened(BoxOpened {
+            box_pda: *box_account.key,
+            sender: escrow_box.sender,
+            recipient: *recipient.key,
+            amount,
+            deadline,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_appeal_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        evidence: AppealEvidence,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let token_box_account = next_account_info(account_info_iter)?;
+        let vault_ata = next_account_info(account_info_iter)?;
+        let recipient_token_account = next_account_info(account_info_iter)?;
+        let sender = next_account_info(account_info_iter)?;
+        let vault_authority_info = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let profile_account = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if token_box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
+
+        Self::check_box_sender(&token_box.sender, token_box.deadline, sender)?;
+
+        let clock = Clock::get()?;
+
+        // The stake goes where an open would send it
+        let recipient = token_box.recovered_payout(clock.unix_timestamp).unwrap_or(token_box.sender);
+        if get_associated_token_address(&recipient, &token_box.mint) != *recipient_token_account.key {
+            return Err(EscrowError::InvalidTokenAccount.into());
+        }
+
+        Self::check_appeal(
+            &token_box.terms,
+            &token_box.reading_mode,
+            token_box.sweep_initiated_at,
+            clock.unix_timestamp,
+            &evidence,
+            account_info_iter.as_slice(),
+        )?;
+
+        // Derive vault authority PDA
+        let (vault_authority, vault_bump) = Pubkey::find_program_address(
+            &[b"vault", token_box_account.key.as_ref()],
+            program_id,
+        );
+
+        // Verify vault authority account matches derived PDA
+        if vault_authority != *vault_authority_info.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let amount = token_box.amount;
+
+        // Transfer tokens from vault back to the sender, or the recovery payout
+        invoke_signed(
+            &token_instruction::transfer(
+                token_program.key,
+                vault_ata.key,
+                recipient_token_account.key,
+                &vault_authority,
+                &[],
+                amount,
+            )?,
+            &[
+                vault_ata.clone(),
+                recipient_token_account.clone(),
+                vault_authority_info.clone(),
+                token_program.clone(),
+            ],
+            &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
+        )?;
+
+        // Close vault ATA and return rent to sender
+        invoke_signed(
+            &token_instruction::close_account(
+                token_program.key,
+                vault_ata.key,
+                sender.key,
+                &vault_authority,
+                &[],
+            )?,
+            &[
+                vault_ata.clone(),
+                sender.clone(),
+                vault_authority_info.clone(),
+                token_program.clone(),
+            ],
+            &[&[b"vault", token_box_account.key.as_ref(), &[vault_bump]]],
+        )?;
+
+        let deadline = token_box.deadline;
+
+        // Mark box as closed
+        token_box.deadline = 0;
+        token_box.amount = 0;
+        token_box.sweep_initiated_at = 0;
+        token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
+
+        let mut profile = Self::load_profile(program_id, &token_box.sender, profile_account)?;
+        profile.record_completed()?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        program_state.record_token_box_opened()?;
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::TokenBoxOpened(TokenBoxOpened {
+            box_pda: *token_box_account.key,
+            sender: token_box.sender,
+            recipient: *recipient_token_account.key,
+            mint: token_box.mint,
+            amount,
+            deadline,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_rotate_box_id(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_id: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let box_account = next_account_info(account_info_iter)?;
+        let sender = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
+
+        Self::check_box_sender(&escrow_box.sender, escrow_box.deadline, sender)?;
+
+        let clock = Clock::get()?;
+        Self::check_rotation(&new_id, escrow_box.now(&clock), escrow_box.effective_deadline(&program_state, &clock))?;
+
+        let old_id = escrow_box.id_key(clock.unix_timestamp);
+        escrow_box.rotated_id = new_id;
+        escrow_box.recovery = Recovery::default();
+        escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::BoxIdRotated(BoxIdRotated {
+            box_pda: *box_account.key,
+            sender: escrow_box.sender,
+            old_id,
+            new_id,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_rotate_box_id_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_id: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let token_box_account = next_account_info(account_info_iter)?;
+        let sender = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if token_box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
+
+        Self::check_box_sender(&token_box.sender, token_box.deadline, sender)?;
+
+        let clock = Clock::get()?;
+        Self::check_rotation(&new_id, token_box.now(&clock), token_box.effective_deadline(&program_state, &clock))?;
+
+        let old_id = token_box.id_key(clock.unix_timestamp);
+        token_box.rotated_id = new_id;
+        token_box.recovery = Recovery::default();
+        token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::BoxIdRotated(BoxIdRotated {
+            box_pda: *token_box_account.key,
+            sender: token_box.sender,
+            old_id,
+            new_id,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_commit_finish(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        proof_hash: [u8; 32],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let box_account = next_account_info(account_info_iter)?;
+        let id_signer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
+
+        let clock = Clock::get()?;
+        let now = escrow_box.now(&clock);
+        Self::check_before_deadline(
+            &escrow_box.id_key(clock.unix_timestamp),
+            id_signer,
+            escrow_box.deadline,
+            now,
+            escrow_box.effective_deadline(&program_state, &clock),
+        )?;
+
+        escrow_box.finish = FinishCommitment {
+            proof_hash,
+            committed_at: now,
+        };
+        escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::FinishCommitted(FinishCommitted {
+            box_pda: *box_account.key,
+            proof_hash,
+            committed_at: now,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_commit_finish_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        proof_hash: [u8; 32],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let token_box_account = next_account_info(account_info_iter)?;
+        let id_signer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if token_box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
+
+        let clock = Clock::get()?;
+        let now = token_box.now(&clock);
+        Self::check_before_deadline(
+            &token_box.id_key(clock.unix_timestamp),
+            id_signer,
+            token_box.deadline,
+            now,
+            token_box.effective_deadline(&program_state, &clock),
+        )?;
+
+        token_box.finish = FinishCommitment {
+            proof_hash,
+            committed_at: now,
+        };
+        token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::FinishCommitted(FinishCommitted {
+            box_pda: *token_box_account.key,
+            proof_hash,
+            committed_at: now,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_set_timer(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        running: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let box_account = next_account_info(account_info_iter)?;
+        let id_signer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
+
+        let clock = Clock::get()?;
+        Self::check_before_deadline(
+            &escrow_box.id_key(clock.unix_timestamp),
+            id_signer,
+            escrow_box.deadline,
+            escrow_box.now(&clock),
+            escrow_box.effective_deadline(&program_state, &clock),
+        )?;
+        Self::set_timer(&escrow_box.reading_mode, &mut escrow_box.timer, running, clock.unix_timestamp)?;
+        escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::TimerSet(TimerSet {
+            box_pda: *box_account.key,
+            running,
+            elapsed: escrow_box.timer.elapsed,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_set_timer_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        running: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let token_box_account = next_account_info(account_info_iter)?;
+        let id_signer = next_account_info(account_info_iter)?;
+        let program_state_account = next_account_info(account_info_iter)?;
+
+        if token_box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let program_state = Self::load_program_state(program_id, program_state_account)?;
+
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
+
+        let clock = Clock::get()?;
+        Self::check_before_deadline(
+            &token_box.id_key(clock.unix_timestamp),
+            id_signer,
+            token_box.deadline,
+            token_box.now(&clock),
+            token_box.effective_deadline(&program_state, &clock),
+        )?;
+        Self::set_timer(&token_box.reading_mode, &mut token_box.timer, running, clock.unix_timestamp)?;
+        token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::TimerSet(TimerSet {
+            box_pda: *token_box_account.key,
+            running,
+            elapsed: token_box.timer.elapsed,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Starts or stops the timer of a `ReadingMode::Timer` box at unix time `now`
+    fn set_timer(reading_mode: &ReadingMode, timer: &mut ReadingTimer, running: bool, now: i64) -> ProgramResult {
+        if !matches!(reading_mode, ReadingMode::Timer { .. }) {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if running {
+            timer.start(now)
+        } else {
+            timer.stop(now)
+        }
+    }
+
+    fn process_recover_box(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_id: Pubkey,
+        payout: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let box_account = next_account_info(account_info_iter)?;
+
+        if box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mut escrow_box = Box::unpack(&box_account.data.borrow())?;
+
+        let clock = Clock::get()?;
+        escrow_box.recovery = Self::guardian_recovery(
+            &escrow_box.guardians,
+            escrow_box.deadline,
+            account_info_iter.as_slice(),
+            new_id,
+            payout,
+            clock.unix_timestamp,
+        )?;
+        escrow_box.serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::RecoveryQueued(RecoveryQueued {
+            box_pda: *box_account.key,
+            sender: escrow_box.sender,
+            new_id,
+            payout,
+            effective_at: escrow_box.recovery.effective_at,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_recover_box_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        new_id: Pubkey,
+        payout: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let token_box_account = next_account_info(account_info_iter)?;
+
+        if token_box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mut token_box = TokenBox::unpack(&token_box_account.data.borrow())?;
+
+        let clock = Clock::get()?;
+        token_box.recovery = Self::guardian_recovery(
+            &token_box.guardians,
+            token_box.deadline,
+            account_info_iter.as_slice(),
+            new_id,
+            payout,
+            clock.unix_timestamp,
+        )?;
+        token_box.serialize(&mut &mut token_box_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::RecoveryQueued(RecoveryQueued {
+            box_pda: *token_box_account.key,
+            sender: token_box.sender,
+            new_id,
+            payout,
+            effective_at: token_box.recovery.effective_at,
+            timestamp: clock.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_set_box_meta(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        fields: BoxMetaFields,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let sender = next_account_info(account_info_iter)?;
+        let box_account = next_account_info(account_info_iter)?;
+        let meta_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        if !sender.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let (box_sender, deadline) = Self::load_any_box(program_id, box_account)?;
+
+        if box_sender != *sender.key {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        if deadline == 0 {
+            return Err(EscrowError::UnknownId.into());
+        }
+
+        Self::write_box_meta(program_id, sender, box_account.key, meta_account, system_program, fields)
+    }
+
+    fn process_close_box_meta(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let sender = next_account_info(account_info_iter)?;
+        let box_account = next_account_info(account_info_iter)?;
+        let meta_account = next_account_info(account_info_iter)?;
+
+        if !sender.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let (box_sender, deadline) = Self::load_any_box(program_id, box_account)?;
+
+        if box_sender != *sender.key {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        if deadline != 0 {
+            return Err(EscrowError::BoxStillActive.into());
+        }
+
+        if meta_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let (meta_pda, _) = Pubkey::find_program_address(
+            &[b"box_meta", box_account.key.as_ref()],
+            program_id,
+        );
+
+        if meta_pda != *meta_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        Self::close_account(meta_account, sender)?;
+
+        EscrowEvent::BoxMetaClosed(BoxMetaClosed {
+            box_pda: *box_account.key,
+            sender: *sender.key,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Writes the labels of a box just created from `entry`; its BoxMeta PDA is the next
+    /// account only when `options.meta` is set
+    fn create_box_meta<'a, 'b, I: Iterator<Item = &'b AccountInfo<'a>>>(
+        program_id: &Pubkey,
+        sender: &AccountInfo<'a>,
+        box_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        entry: &BoxEntry,
+        account_info_iter: &mut I,
+    ) -> ProgramResult
+    where
+        'a: 'b,
+    {
+        let Some(fields) = &entry.options.meta else {
+            return Ok(());
+        };
+
+        let meta_account = next_account_info(account_info_iter)?;
+        Self::write_box_meta(program_id, sender, box_account.key, meta_account, system_program, fields.clone())
+    }
+
+    /// Stores `fields` in the BoxMeta PDA of `box_pda`, creating or resizing the account;
+    /// the sender pays any extra rent and gets back what is no longer needed
+    fn write_box_meta<'a>(
+        program_id: &Pubkey,
+        sender: &AccountInfo<'a>,
+        box_pda: &Pubkey,
+        meta_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        fields: BoxMetaFields,
+    ) -> ProgramResult {
+        fields.validate()?;
+
+        let (meta_pda, bump) = Pubkey::find_program_address(
+            &[b"box_meta", box_pda.as_ref()],
+            program_id,
+        );
+
+        if meta_pda != *meta_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let meta = BoxMeta::new(*box_pda, *sender.key, fields);
+
+        let rent = Rent::get()?;
+        let space = meta.space();
+        let lamports = rent.minimum_balance(space);
+
+        if meta_account.data_is_empty() {
+            Self::create_pda_account(
+                sender,
+                meta_account,
+                system_program,
+                lamports,
+                space,
+                program_id,
+                &[b"box_meta", box_pda.as_ref(), &[bump]],
+            )?;
+        } else {
+            if meta_account.owner != program_id {
+                return Err(ProgramError::InvalidAccountOwner);
+            }
+
+            let current = meta_account.lamports();
+            if current < lamports {
+                invoke(
+                    &system_instruction::transfer(sender.key, meta_account.key, lamports - current),
+                    &[sender.clone(), meta_account.clone(), system_program.clone()],
+                )?;
+            } else {
+                let excess = current - lamports;
+                **meta_account.try_borrow_mut_lamports()? -= excess;
+                **sender.try_borrow_mut_lamports()? += excess;
+            }
+
+            meta_account.resize(space)?;
+        }
+
+        meta.serialize(&mut &mut meta_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::BoxMetaSet(BoxMetaSet {
+            box_pda: *box_pda,
+            sender: *sender.key,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Sender and deadline of a SOL or token box, told apart by account kind
+    fn load_any_box(
+        program_id: &Pubkey,
+        box_account: &AccountInfo,
+    ) -> Result<(Pubkey, i64), ProgramError> {
+        if box_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let data = box_account.data.borrow();
+        if data.len() == LegacyBox::LEN || data.len() == LegacyTokenBox::LEN {
+            return Err(EscrowError::AccountNotMigrated.into());
+        }
+
+        match data.first() {
+            Some(&kind) if kind == AccountKind::Box as u8 => {
+                let escrow_box = Box::unpack(&data)?;
+                Ok((escrow_box.sender, escrow_box.deadline))
+            }
+            Some(&kind) if kind == AccountKind::TokenBox as u8 => {
+                let token_box = TokenBox::unpack(&data)?;
+                Ok((token_box.sender, token_box.deadline))
+            }
+            _ => Err(ProgramError::InvalidAccountData),
+        }
+    }
+
+    fn process_claim_referral_rewards(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let referrer = next_account_info(account_info_iter)?;
+        let referral_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        if !referrer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let (referral_pda, bump) = Pubkey::find_program_address(
+            &[b"referral", referrer.key.as_ref()],
+            program_id,
+        );
+
+        if referral_pda != *referral_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let rent = Rent::get()?;
+
+        // First call: open the PDA, which holds no rewards yet
+        if referral_account.data_is_empty() {
+            Self::create_pda_account(
+                referrer,
+                referral_account,
+                system_program,
+                rent.minimum_balance(Referral::LEN),
+                Referral::LEN,
+                program_id,
+                &[b"referral", referrer.key.as_ref(), &[bump]],
+            )?;
+
+            let referral = Referral::new(*referrer.key);
+            referral.serialize(&mut &mut referral_account.data.borrow_mut()[..])?;
+            return Ok(());
+        }
+
+        if referral_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let mut referral = Referral::unpack(&referral_account.data.borrow())?;
+
+        let amount = referral_account
+            .lamports()
+            .saturating_sub(rent.minimum_balance(Referral::LEN));
+
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        **referral_account.try_borrow_mut_lamports()? -= amount;
+        **referrer.try_borrow_mut_lamports()? += amount;
+
+        referral.total_claimed = referral
+            .total_claimed
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        referral.serialize(&mut &mut referral_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::ReferralRewardsClaimed(ReferralRewardsClaimed {
+            referrer: *referrer.key,
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    fn process_migrate_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_account_info(account_info_iter)?;
+        let account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let version = match account.data_len() {
+            LegacyProgramState::LEN => {
+                let treasury_account = next_account_info(account_info_iter)?;
+                Self::migrate_program_state(program_id, payer, account, system_program, treasury_account)?;
+                ProgramState::VERSION
+            }
+            LegacyBox::LEN | LegacyTokenBox::LEN => {
+                let program_state_account = next_account_info(account_info_iter)?;
+                let profile_account = next_account_info(account_info_iter)?;
+                Self::migrate_box(program_id, payer, account, system_program, program_state_account, profile_account)?
+            }
+            // Already in a versioned layout
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        EscrowEvent::AccountMigrated(AccountMigrated {
+            account: *account.key,
+            version,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Rewrites the first program state layout, which only held the authority, with the
+    /// default config, creating the treasury PDA that layout had no use for
+    fn migrate_program_state<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        program_state_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        treasury_account: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        let (program_state_pda, _) = Pubkey::find_program_address(
+            &[b"program_state"],
+            program_id,
+        );
+
+        if program_state_pda != *program_state_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let legacy = LegacyProgramState::try_from_slice(&program_state_account.data.borrow())?;
+
+        let (treasury_pda, treasury_bump) = Pubkey::find_program_address(
+            &[b"treasury"],
+            program_id,
+        );
+
+        if treasury_pda != *treasury_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        if treasury_account.owner != program_id {
+            Self::create_pda_account(
+                payer,
+                treasury_account,
+                system_program,
+                Rent::get()?.minimum_balance(0),
+                0,
+                program_id,
+                &[b"treasury", &[treasury_bump]],
+            )?;
+        }
+
+        Self::resize_account(payer, program_state_account, system_program, ProgramState::LEN)?;
+
+        let program_state = ProgramState::new(legacy.authority, treasury_pda);
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    /// Rewrites an open box of the first layout under the fixed legacy terms, counting
+    /// it in the program state and its sender's profile as if created now.
+    /// Returns the version written.
+    fn migrate_box<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        box_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        program_state_account: &AccountInfo<'a>,
+        profile_account: &AccountInfo<'a>,
+    ) -> Result<u8, ProgramError> {
+        let mut program_state = Self::load_program_state(program_id, program_state_account)?;
+        // Not the configured terms: migration is permissionless, so anyone could otherwise
+        // move a box onto whatever terms the authority has configured since it was created
+        let (treasury_pda, _) = Pubkey::find_program_address(&[b"treasury"], program_id);
+
+        let data = box_account.data.borrow().to_vec();
+        let (sender, lamports, version) = if data.len() == LegacyBox::LEN {
+            let legacy = LegacyBox::try_from_slice(&data)?;
+            Self::check_legacy_box(program_id, b"box", &legacy.sender, &legacy.id, legacy.deadline, box_account)?;
+
+            let (sender, amount) = (legacy.sender, legacy.amount);
+            Self::resize_account(payer, box_account, system_program, Box::LEN)?;
+            Box::from_legacy(legacy, treasury_pda)
+                .serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+            program_state.record_box_created(amount)?;
+            (sender, amount, Box::VERSION)
+        } else {
+            let legacy = LegacyTokenBox::try_from_slice(&data)?;
+            Self::check_legacy_box(program_id, b"token_box", &legacy.sender, &legacy.id, legacy.deadline, box_account)?;
+
+            let sender = legacy.sender;
+            Self::resize_account(payer, box_account, system_program, TokenBox::LEN)?;
+            TokenBox::from_legacy(legacy, treasury_pda)
+                .serialize(&mut &mut box_account.data.borrow_mut()[..])?;
+
+            program_state.record_token_box_created()?;
+            (sender, 0, TokenBox::VERSION)
+        };
+
+        program_state.serialize(&mut &mut program_state_account.data.borrow_mut()[..])?;
+
+        let mut profile = Self::load_or_create_profile(program_id, payer, &sender, profile_account, system_program)?;
+        profile.record_created(lamports)?;
+        profile.serialize(&mut &mut profile_account.data.borrow_mut()[..])?;
+
+        Ok(version)
+    }
+
+    /// Checks a first-layout box is the PDA of its sender and id and still open
+    fn check_legacy_box(
+        program_id: &Pubkey,
+        seed: &[u8],
+        sender: &Pubkey,
+        id: &Pubkey,
+        deadline: i64,
+        box_account: &AccountInfo,
+    ) -> ProgramResult {
+        let (box_pda, _) = Pubkey::find_program_address(
+            &[seed, sender.as_ref(), id.as_ref()],
+            program_id,
+        );
+
+        if box_pda != *box_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        // Opened and swept boxes are never read again
+        if deadline == 0 {
+            return Err(EscrowError::UnknownId.into());
+        }
+
+        Ok(())
+    }
+
+    /// Closes a program account, moving all its lamports to `destination`. The data is zeroed
+    /// rather than resized, so that until the runtime removes the account at the end of the
+    /// transaction its kind byte (0) matches no account kind.
+    fn close_account(account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+        let lamports = account.lamports();
+        **account.try_borrow_mut_lamports()? -= lamports;
+        **destination.try_borrow_mut_lamports()? += lamports;
+        account.data.borrow_mut().fill(0);
+        Ok(())
+    }
+
+    /// Grows a program account to `space` bytes; `payer` covers the extra rent
+    fn resize_account<'a>(
+        payer: &AccountInfo<'a>,
+        account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        space: usize,
+    ) -> ProgramResult {
+        let rent = Rent::get()?;
+        let extra = rent
+            .minimum_balance(space)
+            .saturating_sub(rent.minimum_balance(account.data_len()));
+
+        if extra > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, account.key, extra),
+                &[payer.clone(), account.clone(), system_program.clone()],
+            )?;
+        }
+
+        account.resize(space)
+    }
+
+    /// Records the referrer named in `options`. Only a sender's first box can name one;
+    /// naming the same referrer again later is accepted and changes nothing.
+    /// The self-referral check compares keys only: it stops a sender naming their own wallet,
+    /// not a second wallet they control. Such a referrer can win back at most
+    /// `referral_share_bps` of the sender's own forfeits to the treasury.
+    fn set_referrer(profile: &mut UserProfile, options: &BoxOptions) -> ProgramResult {
+        let Some(referrer) = options.referrer else {
+            return Ok(());
+        };
+
+        if referrer == profile.referrer {
+            return Ok(());
+        }
+
+        if referrer == profile.owner || referrer == Pubkey::default() {
+            return Err(EscrowError::SelfReferral.into());
+        }
+
+        if profile.referrer != Pubkey::default() || profile.boxes_created != 0 {
+            return Err(EscrowError::ReferrerLocked.into());
+        }
+
+        profile.referrer = referrer;
+
+        EscrowEvent::ReferrerSet(ReferrerSet {
+            sender: profile.owner,
+            referrer,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Account the referrer's share of a forfeit is paid to, passed after the fixed accounts
+    /// of a forfeit whenever the sender has a referrer
+    fn next_referral_account<'a, 'b, I: Iterator<Item = &'b AccountInfo<'a>>>(
+        profile: &UserProfile,
+        account_info_iter: &mut I,
+    ) -> Result<Option<&'b AccountInfo<'a>>, ProgramError> {
+        if profile.referrer == Pubkey::default() {
+            return Ok(None);
+        }
+
+        next_account_info(account_info_iter).map(Some)
+    }
+
+    /// Referrer's share of a forfeit of `amount` going to `beneficiary`. Only forfeits to the
+    /// treasury are shared; other beneficiaries keep the whole amount.
+    fn forfeit_referral_share(
+        program_id: &Pubkey,
+        program_state: &ProgramState,
+        beneficiary: &Pubkey,
+        amount: u64,
+    ) -> u64 {
+        let (treasury_pda, _) = Pubkey::find_program_address(&[b"treasury"], program_id);
+
+        if *beneficiary != treasury_pda {
+            return 0;
+        }
+
+        program_state.config.referral_share(amount)
+    }
+
+    /// Moves `share` lamports of a forfeited SOL box into the referrer's Referral PDA and
+    /// returns the amount moved. Until the referrer has opened the PDA with
+    /// `ClaimReferralRewards` nothing is moved, the beneficiary keeps the share and
+    /// `ReferralSkipped` records it.
+    fn accrue_referral(
+        program_id: &Pubkey,
+        box_account: &AccountInfo,
+        referral_account: &AccountInfo,
+        profile: &UserProfile,
+        share: u64,
+    ) -> Result<u64, ProgramError> {
+        let (referral_pda, _) = Pubkey::find_program_address(
+            &[b"referral", profile.referrer.as_ref()],
+            program_id,
+        );
+
+        if referral_pda != *referral_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        if share == 0 {
+            return Ok(0);
+        }
+
+        if referral_account.owner != program_id {
+            Self::skip_referral(profile, None, share)?;
+            return Ok(0);
+        }
+
+        let mut referral = Referral::unpack(&referral_account.data.borrow())?;
+
+        **box_account.try_borrow_mut_lamports()? -= share;
+        **referral_account.try_borrow_mut_lamports()? += share;
+
+        referral.total_earned = referral
+            .total_earned
+            .checked_add(share)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        referral.serialize(&mut &mut referral_account.data.borrow_mut()[..])?;
+
+        EscrowEvent::ReferralAccrued(ReferralAccrued {
+            referrer: profile.referrer,
+            sender: profile.owner,
+            mint: None,
+            amount: share,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(share)
+    }
+
+    /// Pays `share` of a forfeited token box from its vault to the referrer's ATA of the box's
+    /// mint and returns the amount paid. While that ATA does not exist nothing is paid, the
+    /// beneficiary keeps the share and `ReferralSkipped` records it.
+    fn pay_token_referral<'a>(
+        vault: &VaultAccounts<'_, 'a>,
+        token_box: &TokenBox,
+        referral_token_account: &AccountInfo<'a>,
+        profile: &UserProfile,
+        share: u64,
+    ) -> Result<u64, ProgramError> {
+        let expected = get_associated_token_address(&profile.referrer, &token_box.mint);
+        if expected != *referral_token_account.key {
+            return Err(EscrowError::InvalidTokenAccount.into());
+        }
+
+        if share == 0 {
+            return Ok(0);
+        }
+
+        if *referral_token_account.owner != spl_token::id() {
+            Self::skip_referral(profile, Some(token_box.mint), share)?;
+            return Ok(0);
+        }
+
+        invoke_signed(
+            &token_instruction::transfer(
+                vault.token_program.key,
+                vault.vault_ata.key,
+                referral_token_account.key,
+                vault.vault_authority.key,
+                &[],
+                share,
+            )?,
+            &[
+                vault.vault_ata.clone(),
+                referral_token_account.clone(),
+                vault.vault_authority.clone(),
+                vault.token_program.clone(),
+            ],
+            &[&[b"vault", vault.token_box.key.as_ref(), &[vault.vault_bump]]],
+        )?;
+
+        EscrowEvent::ReferralAccrued(ReferralAccrued {
+            referrer: profile.referrer,
+            sender: profile.owner,
+            mint: Some(token_box.mint),
+            amount: share,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()?;
+
+        Ok(share)
+    }
+
+    /// Records a referral share of `mint` (None = lamports) left with the beneficiary
+    fn skip_referral(profile: &UserProfile, mint: Option<Pubkey>, share: u64) -> ProgramResult {
+        EscrowEvent::ReferralSkipped(ReferralSkipped {
+            referrer: profile.referrer,
+            sender: profile.owner,
+            mint,
+            amount: share,
+            timestamp: Clock::get()?.unix_timestamp,
+        })
+        .emit()
+    }
+
+    fn badge_accounts<'a, 'b, I: Iterator<Item = &'b AccountInfo<'a>>>(
+        account_info_iter: &mut I,
+    ) -> Result<BadgeAccounts<'b, 'a>, ProgramError>
+    where
+        'a: 'b,
+    {
+        Ok(BadgeAccounts {
+            payer: next_account_info(account_info_iter)?,
+            badge_mint: next_account_info(account_info_iter)?,
+            badge_authority: next_account_info(account_info_iter)?,
+            owner_token_account: next_account_info(account_info_iter)?,
+            owner: next_account_info(account_info_iter)?,
+            token_program: next_account_info(account_info_iter)?,
+            associated_token_program: next_account_info(account_info_iter)?,
+            system_program: next_account_info(account_info_iter)?,
+        })
+    }
+
+    /// Mints a one-off non-transferable Token-2022 badge for an opened box to `owner`.
+    /// The badge mint carries its own metadata (book title, content hash and completion
+    /// date) and its mint authority is dropped once the single badge is minted.
+    /// Makes about ten CPIs; see `OpenBox` for the compute budget.
+    fn mint_badge(
+        program_id: &Pubkey,
+        badge: &BadgeAccounts,
+        box_pda: &Pubkey,
+        owner: &Pubkey,
+        book: &BookRef,
+        now: i64,
+    ) -> ProgramResult {
+        let BadgeAccounts {
+            payer,
+            badge_mint,
+            badge_authority,
+            owner_token_account,
+            owner: owner_info,
+            token_program,
+            associated_token_program,
+            system_program,
+        } = *badge;
+
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *token_program.key != spl_token_2022::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        if owner_info.key != owner {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        let (mint_pda, mint_bump) = Pubkey::find_program_address(
+            &[b"badge", box_pda.as_ref()],
+            program_id,
+        );
+
+        if mint_pda != *badge_mint.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let (authority, authority_bump) = Pubkey::find_program_address(&[b"badge_authority"], program_id);
+
+        if authority != *badge_authority.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let authority_seeds: &[&[u8]] = &[b"badge_authority", &[authority_bump]];
+
+        let name = match book.title() {
+            "" => BADGE_DEFAULT_NAME.to_string(),
+            title => title.to_string(),
+        };
+        let content_hash = Self::hex(&book.content_hash);
+        let metadata = TokenMetadata {
+            mint: mint_pda,
+            name,
+            symbol: BADGE_SYMBOL.to_string(),
+            additional_metadata: vec![
+                ("content_hash".to_string(), content_hash),
+                ("completed_at".to_string(), Self::badge_date(now)),
+            ],
+            ..TokenMetadata::default()
+        };
+
+        // The metadata is written by the token program after the mint is initialized,
+        // growing the account, so its rent is paid upfront
+        let space = ExtensionType::try_calculate_account_len::<Token2022Mint>(&[
+            ExtensionType::NonTransferable,
+            ExtensionType::MetadataPointer,
+        ])?;
+        let rent = Rent::get()?;
+        let lamports = rent.minimum_balance(space.checked_add(metadata.tlv_size_of()?).ok_or(ProgramError::ArithmeticOverflow)?);
+
+        Self::create_pda_account(
+            payer,
+            badge_mint,
+            system_program,
+            lamports,
+            space,
+            token_program.key,
+            &[b"badge", box_pda.as_ref(), &[mint_bump]],
+        )?;
+
+        invoke(
+            &token_2022_instruction::initialize_non_transferable_mint(token_program.key, badge_mint.key)?,
+            &[badge_mint.clone(), token_program.clone()],
+        )?;
+
+        invoke(
+            &metadata_pointer::instruction::initialize(
+                token_program.key,
+                badge_mint.key,
+                Some(authority),
+                Some(mint_pda),
+            )?,
+            &[badge_mint.clone(), token_program.clone()],
+        )?;
+
+        invoke(
+            &token_2022_instruction::initialize_mint2(token_program.key, badge_mint.key, &authority, None, 0)?,
+            &[badge_mint.clone(), token_program.clone()],
+        )?;
+
+        invoke_signed(
+            &metadata_instruction::initialize(
+                token_program.key,
+                badge_mint.key,
+                &authority,
+                badge_mint.key,
+                &authority,
+                metadata.name.clone(),
+                metadata.symbol.clone(),
+                metadata.uri.clone(),
+            ),
+            &[badge_mint.clone(), badge_authority.clone(), token_program.clone()],
+            &[authority_seeds],
+        )?;
+
+        for (key, value) in &metadata.additional_metadata {
+            invoke_signed(
+                &metadata_instruction::update_field(
+                    token_program.key,
+                    badge_mint.key,
+                    &authority,
+                    Field::Key(key.clone()),
+                    value.clone(),
+                ),
+                &[badge_mint.clone(), badge_authority.clone(), token_program.clone()],
+                &[authority_seeds],
+            )?;
+        }
+
+        invoke(
+            &ata_instruction::create_associated_token_account_idempotent(
+                payer.key,
+                owner,
+                badge_mint.key,
+                token_program.key,
+            ),
+            &[
+                payer.clone(),
+                owner_token_account.clone(),
+                owner_info.clone(),
+                badge_mint.clone(),
+                system_program.clone(),
+                token_program.clone(),
+                associated_token_program.clone(),
+            ],
+        )?;
+
+        invoke_signed(
+            &token_2022_instruction::mint_to(
+                token_program.key,
+                badge_mint.key,
+                owner_token_account.key,
+                &authority,
+                &[],
+                1,
+            )?,
+            &[badge_mint.clone(), owner_token_account.clone(), badge_authority.clone(), token_program.clone()],
+            &[authority_seeds],
+        )?;
+
+        // One badge per box: no further supply
+        invoke_signed(
+            &token_2022_instruction::set_authority(
+                token_program.key,
+                badge_mint.key,
+                None,
+                token_2022_instruction::AuthorityType::MintTokens,
+                &authority,
+                &[],
+            )?,
+            &[badge_mint.clone(), badge_authority.clone(), token_program.clone()],
+            &[authority_seeds],
+        )?;
+
+        EscrowEvent::BadgeMinted(BadgeMinted {
+            box_pda: *box_pda,
+            owner: *owner,
+            badge_mint: mint_pda,
+            content_hash: book.content_hash,
+            timestamp: now,
+        })
+        .emit()?;
+
+        Ok(())
+    }
+
+    /// Lowercase hex of `bytes`, without the per-byte formatting machinery of `format!`
+    fn hex(bytes: &[u8]) -> String {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+        let mut hex = String::with_capacity(bytes.len() * 2);
+        for byte in bytes {
+            hex.push(DIGITS[(byte >> 4) as usize] as char);
+            hex.push(DIGITS[(byte & 0x0f) as usize] as char);
+        }
+        hex
+    }
+
+    /// UTC calendar date (YYYY-MM-DD) of a unix timestamp
+    fn badge_date(timestamp: i64) -> String {
+        // Days to civil date, proleptic Gregorian calendar
+        let days = timestamp.div_euclid(86400) + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        format!("{year:04}-{month:02}-{day:02}")
+    }
+
+    /// Checks that at least the threshold of distinct guardians signed
+    fn check_guardians(guardians: &GuardianSet, signers: &[AccountInfo]) -> ProgramResult {
+        if guardians.threshold == 0 {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        let members = guardians.members();
+        let mut approved = [false; MAX_GUARDIANS];
+        for signer in signers.iter().filter(|signer| signer.is_signer) {
+            if let Some(index) = members.iter().position(|key| key == signer.key) {
+                approved[index] = true;
+            }
+        }
+
+        let approvals = approved.iter().filter(|approved| **approved).count();
+        if approvals < guardians.threshold as usize {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        Ok(())
+    }
+
+    /// Checks `sender` is the sender of an open box and signed
+    fn check_box_sender(box_sender: &Pubkey, deadline: i64, sender: &AccountInfo) -> ProgramResult {
+        if deadline == 0 {
+            return Err(EscrowError::UnknownId.into());
+        }
+
+        if !sender.is_signer || sender.key != box_sender {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks a box key can be rotated to `new_id`: only before the (effective) deadline,
+    /// in the units of the box's deadline kind
+    fn check_rotation(new_id: &Pubkey, now: i64, effective_deadline: i64) -> ProgramResult {
+        if *new_id == Pubkey::default() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if now >= effective_deadline {
+            return Err(EscrowError::TooLate.into());
+        }
+
+        Ok(())
+    }
+
+    /// Recovery of an open box approved by its guardians among `signers`, taking effect
+    /// `RECOVERY_DELAY` after `now`
+    fn guardian_recovery(
+        guardians: &GuardianSet,
+        deadline: i64,
+        signers: &[AccountInfo],
+        new_id: Pubkey,
+        payout: Pubkey,
+        now: i64,
+    ) -> Result<Recovery, ProgramError> {
+        if deadline == 0 {
+            return Err(EscrowError::UnknownId.into());
+        }
+
+        Self::check_guardians(guardians, signers)?;
+
+        if new_id == Pubkey::default() || payout == Pubkey::default() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let effective_at = now
+            .checked_add(RECOVERY_DELAY)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        Ok(Recovery {
+            new_id,
+            payout,
+            effective_at,
+        })
+    }
+
+    /// Checks a sweep can be initiated on an open box: none pending and its grace period over.
+    /// `now` and `sweepable_at` are in the units of the box's deadline kind.
+    fn check_sweep_initiable(deadline: i64, sweep_initiated_at: i64, now: i64, sweepable_at: i64) -> ProgramResult {
+        if deadline == 0 {
+            return Err(EscrowError::UnknownId.into());
+        }
+
+        if sweep_initiated_at != 0 {
+            return Err(EscrowError::SweepAlreadyInitiated.into());
+        }
+
+        if now < sweepable_at {
+            return Err(EscrowError::NotExpired.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks a pending sweep's dispute window has passed
+    fn check_finalizable(terms: &BoxTerms, sweep_initiated_at: i64, now: i64) -> ProgramResult {
+        if sweep_initiated_at == 0 {
+            return Err(EscrowError::SweepNotInitiated.into());
+        }
+
+        if now < terms.finalizable_at(sweep_initiated_at) {
+            return Err(EscrowError::DisputeWindowOpen.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks an appeal arrives during a pending sweep's dispute window with valid evidence
+    fn check_appeal(
+        terms: &BoxTerms,
+        reading_mode: &ReadingMode,
+        sweep_initiated_at: i64,
+        now: i64,
+        evidence: &AppealEvidence,
+        evidence_accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        if sweep_initiated_at == 0 {
+            return Err(EscrowError::SweepNotInitiated.into());
+        }
+
+        if now >= terms.finalizable_at(sweep_initiated_at) {
+            return Err(EscrowError::TooLate.into());
+        }
+
+        match evidence {
+            AppealEvidence::Attestation => {
+                let attestor = evidence_accounts.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+                if terms.attestor == Pubkey::default()
+                    || *attestor.key != terms.attestor
+                    || !attestor.is_signer
+                {
+                    return Err(EscrowError::Unauthorized.into());
+                }
+            }
+            AppealEvidence::CheckpointReveal { codes } => {
+                if !reading_mode.verify_checkpoints(codes) {
+                    return Err(EscrowError::InvalidReadingProof.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the proof submitted on open against the box's reading mode and, for a timer,
+    /// the time its timer counted
+    fn check_reading_proof(reading_mode: &ReadingMode, timer: &ReadingTimer, proof: &ReadingProof) -> ProgramResult {
+        match (reading_mode, proof) {
+            (ReadingMode::Unspecified, _) => Ok(()),
+            (ReadingMode::Checkpoints { .. }, ReadingProof::Checkpoints { codes }) => {
+                if !reading_mode.verify_checkpoints(codes) {
+                    return Err(EscrowError::InvalidReadingProof.into());
+                }
+                Ok(())
+            }
+            (ReadingMode::Timer { target_hours }, ReadingProof::Empty) => {
+                // Only stopped runs count: they were stopped before the deadline
+                if timer.is_running() {
+                    return Err(EscrowError::TimerRunning.into());
+                }
+                if timer.elapsed < *target_hours as i64 * 3600 {
+                    return Err(EscrowError::InvalidReadingProof.into());
+                }
+                Ok(())
+            }
+            _ => Err(EscrowError::InvalidReadingProof.into()),
+        }
+    }
+
+    /// Arbiter named in `options`; a sender cannot judge their own box
+    fn box_arbiter(sender: &Pubkey, options: &BoxOptions) -> Result<Pubkey, ProgramError> {
+        match options.arbiter {
+            Some(arbiter) if arbiter == *sender || arbiter == Pubkey::default() => {
+                Err(ProgramError::InvalidArgument)
+            }
+            Some(arbiter) => Ok(arbiter),
+            None => Ok(Pubkey::default()),
+        }
+    }
+
+    /// Reading mode named in `options`
+    fn box_reading_mode(options: &BoxOptions) -> Result<ReadingMode, ProgramError> {
+        if !options.reading_mode.is_valid() {
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(options.reading_mode)
+    }
+
+    /// Book named in `options`, if any
+    fn box_book(options: &BoxOptions) -> Result<BookRef, ProgramError> {
+        BookRef::new(
+            options.content_hash.unwrap_or_default(),
+            options.title.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Checks that the key currently controlling a box signed
+    fn check_id_signer(id: &Pubkey, id_signer: &AccountInfo) -> ProgramResult {
+        if id_signer.key != id || !id_signer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Ok(())
+    }
+
+    /// Checks a box can still be opened: before its deadline, or during the grace period
+    /// with the proof committed to before the deadline.
+    /// All times are in the units of the box's deadline kind.
+    fn check_open_window(
+        effective_deadline: i64,
+        sweepable_at: i64,
+        now: i64,
+        finish: &FinishCommitment,
+        proof: &ReadingProof,
+    ) -> ProgramResult {
+        if now < effective_deadline {
+            return Ok(());
+        }
+
+        if now >= sweepable_at {
+            return Err(EscrowError::TooLate.into());
+        }
+
+        if !finish.covers(&proof.hash(), effective_deadline) {
+            return Err(EscrowError::TooLate.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks the id key signed for an open box whose deadline (in the units of its deadline
+    /// kind) has not passed
+    fn check_before_deadline(id: &Pubkey, id_signer: &AccountInfo, deadline: i64, now: i64, effective_deadline: i64) -> ProgramResult {
+        if deadline == 0 {
+            return Err(EscrowError::UnknownId.into());
+        }
+
+        Self::check_id_signer(id, id_signer)?;
+
+        if now >= effective_deadline {
+            return Err(EscrowError::TooLate.into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `authority` is the program authority and that it approved the instruction:
+    /// by its own signature, or, once a signer set is configured, by at least `threshold`
+    /// distinct members of the set signing among `signers` (the trailing accounts).
+    fn check_authority(
+        program_state: &ProgramState,
+        authority: &AccountInfo,
+        signers: &[AccountInfo],
+    ) -> ProgramResult {
+        let config = &program_state.config;
+
+        if *authority.key != config.authority {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        if config.threshold == 0 {
+            if !authority.is_signer {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            return Ok(());
+        }
+
+        let signer_set = config.signer_set();
+        let mut approved = [false; MAX_SIGNERS];
+        for signer in signers.iter().filter(|signer| signer.is_signer) {
+            if let Some(index) = signer_set.iter().position(|key| key == signer.key) {
+                approved[index] = true;
+            }
+        }
+
+        let approvals = approved.iter().filter(|approved| **approved).count();
+        if approvals < config.threshold as usize {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        Ok(())
+    }
+
+    /// Loads the profile of `owner`, creating the account, paid by `payer`, on their first box
+    fn load_or_create_profile<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        owner: &Pubkey,
+        profile_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+    ) -> Result<UserProfile, ProgramError> {
+        if !profile_account.data_is_empty() {
+            return Self::load_profile(program_id, owner, profile_account);
+        }
+
+        let (profile_pda, bump) = Pubkey::find_program_address(
+            &[b"profile", owner.as_ref()],
+            program_id,
+        );
+
+        if profile_pda != *profile_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let rent = Rent::get()?;
+        let space = UserProfile::LEN;
+        let lamports = rent.minimum_balance(space);
+
+        Self::create_pda_account(
+            payer,
+            profile_account,
+            system_program,
+            lamports,
+            space,
+            program_id,
+            &[b"profile", owner.as_ref(), &[bump]],
+        )?;
+
+        Ok(UserProfile::new(*owner))
+    }
+
+    /// Creates the PDA `account` with `space` bytes owned by `owner`, funded by `payer`.
+    /// `create_account` refuses an address that already holds lamports, and anyone can
+    /// send lamports to a PDA before it exists, so such an account is instead topped up
+    /// to `lamports`, then allocated and assigned, as the associated token program does.
+    fn create_pda_account<'a>(
+        payer: &AccountInfo<'a>,
+        account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        lamports: u64,
+        space: usize,
+        owner: &Pubkey,
+        seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let current = account.lamports();
+
+        if current == 0 {
+            return invoke_signed(
+                &system_instruction::create_account(payer.key, account.key, lamports, space as u64, owner),
+                &[payer.clone(), account.clone(), system_program.clone()],
+                &[seeds],
+            );
+        }
+
+        if current < lamports {
+            invoke(
+                &system_instruction::transfer(payer.key, account.key, lamports - current),
+                &[payer.clone(), account.clone(), system_program.clone()],
+            )?;
+        }
+
+        invoke_signed(
+            &system_instruction::allocate(account.key, space as u64),
+            &[account.clone(), system_program.clone()],
+            &[seeds],
+        )?;
+
+        invoke_signed(
+            &system_instruction::assign(account.key, owner),
+            &[account.clone(), system_program.clone()],
+            &[seeds],
+        )
+    }
+
+    /// Loads the profile of `owner`, checking the account is that owner's profile PDA
+    fn load_profile(
+        program_id: &Pubkey,
+        owner: &Pubkey,
+        profile_account: &AccountInfo,
+    ) -> Result<UserProfile, ProgramError> {
+        if profile_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let (profile_pda, _) = Pubkey::find_program_address(
+            &[b"profile", owner.as_ref()],
+            program_id,
+        );
+
+        if profile_pda != *profile_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        UserProfile::unpack(&profile_account.data.borrow())
+    }
+
+    /// Loads the program state, checking the account is the program state PDA
+    fn load_program_state(
+        program_id: &Pubkey,
+        program_state_account: &AccountInfo,
+    ) -> Result<ProgramState, ProgramError> {
+        if program_state_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountOwner);
+        }
+
+        let (program_state_pda, _) = Pubkey::find_program_address(
+            &[b"program_state"],
+            program_id,
+        );
+
+        if program_state_pda != *program_state_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        ProgramState::unpack(&program_state_account.data.borrow())
+    }
+
+    /// Loads the allowlist entry of `mint`, rejecting mints that are not allowed
+    /// or that gained a freeze authority the entry does not permit
+    fn load_mint_config(
+        program_id: &Pubkey,
+        mint: &AccountInfo,
+        mint_config_account: &AccountInfo,
+    ) -> Result<MintConfig, ProgramError> {
+        let (mint_config_pda, _) = Pubkey::find_program_address(
+            &[b"mint_config", mint.key.as_ref()],
+            program_id,
+        );
+
+        if mint_config_pda != *mint_config_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        if mint_config_account.owner != program_id || mint_config_account.data_is_empty() {
+            return Err(EscrowError::MintNotAllowed.into());
+        }
+
+        let mint_config = MintConfig::unpack(&mint_config_account.data.borrow())?;
+
+        // Checked like `AddMint` does: any program could own an account at the mint's key
+        // shaped like a mint
+        if *mint.owner != spl_token::id() {
+            return Err(EscrowError::InvalidMint.into());
+        }
+
+        let mint_state = spl_token::state::Mint::unpack(&mint.data.borrow())
+            .map_err(|_| EscrowError::InvalidMint)?;
+
+        if mint_state.freeze_authority.is_some() && !mint_config.allow_freeze_authority {
+            return Err(EscrowError::FreezableMint.into());
+        }
+
+        Ok(mint_config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instruction::{self as builder, OpenOptions},
+        state::{MAX_META_NOTE_LEN, MAX_TITLE_LEN, MIN_DISPUTE_WINDOW},
+        test_stubs,
+    };
+    use solana_program::{
+        entrypoint::{deserialize, MAX_PERMITTED_DATA_INCREASE},
+        instruction::Instruction,
+    };
+    use std::collections::HashMap;
+
+    const NOW: i64 = 1_700_000_000;
+    const STAKE: u64 = 1_000_000_000;
+
+    /// Account as the runtime hands it to the program
+    #[derive(Clone, Default)]
+    struct TestAccount {
+        lamports: u64,
+        data: Vec<u8>,
+        owner: Pubkey,
+    }
+
+    /// Program-owned account holding `data`, rent exempt like the accounts the program creates
+    fn program_account(data: Vec<u8>) -> TestAccount {
+        TestAccount {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: crate::id(),
+        }
+    }
+
+    /// Runs `instruction` through the entrypoint's input format, so the processor sees exactly
+    /// the builder's account metas, and stores the accounts it leaves behind. Missing accounts
+    /// are empty system accounts. Cross-program invocations do nothing under the test stubs,
+    /// so accounts they would create must already exist.
+    fn process(accounts: &mut HashMap<Pubkey, TestAccount>, instruction: &Instruction) -> ProgramResult {
+        let mut input = Vec::new();
+        input.extend_from_slice(&(instruction.accounts.len() as u64).to_le_bytes());
+
+        for (position, meta) in instruction.accounts.iter().enumerate() {
+            if let Some(first) = instruction.accounts[..position].iter().position(|other| other.pubkey == meta.pubkey) {
+                input.push(first as u8);
+                input.extend_from_slice(&[0; 7]);
+                continue;
+            }
+
+            let account = accounts.get(&meta.pubkey).cloned().unwrap_or_default();
+            input.extend_from_slice(&[u8::MAX, meta.is_signer as u8, meta.is_writable as u8, 0, 0, 0, 0, 0]);
+            input.extend_from_slice(meta.pubkey.as_ref());
+            input.extend_from_slice(account.owner.as_ref());
+            input.extend_from_slice(&account.lamports.to_le_bytes());
+            input.extend_from_slice(&(account.data.len() as u64).to_le_bytes());
+            input.extend_from_slice(&account.data);
+            input.resize((input.len() + MAX_PERMITTED_DATA_INCREASE).next_multiple_of(8), 0);
+            input.extend_from_slice(&0u64.to_le_bytes()); // rent epoch
+        }
+
+        input.extend_from_slice(&(instruction.data.len() as u64).to_le_bytes());
+        input.extend_from_slice(&instruction.data);
+        input.extend_from_slice(instruction.program_id.as_ref());
+
+        // The entrypoint reads the u64s in place, so the input needs their alignment
+        let mut aligned = vec![0u64; input.len().div_ceil(8)];
+        let buffer = aligned.as_mut_ptr() as *mut u8;
+        unsafe { std::ptr::copy_nonoverlapping(input.as_ptr(), buffer, input.len()) };
+        let (program_id, infos, data) = unsafe { deserialize(buffer) };
+
+        Processor::process(program_id, &infos, data)?;
+
+        for info in &infos {
+            let account = TestAccount {
+                lamports: info.lamports(),
+                data: info.data.borrow().to_vec(),
+                owner: *info.owner,
+            };
+            accounts.insert(*info.key, account);
+        }
+
+        Ok(())
+    }
+
+    /// Initialized program sharing a quarter of treasury forfeits with referrers and paying
+    /// keepers a tenth, with a profile for `sender` that has no boxes yet
+    fn setup(sender: &Pubkey) -> HashMap<Pubkey, TestAccount> {
+        let program_id = crate::id();
+        let treasury = builder::get_treasury_address(&program_id);
+
+        let mut program_state = ProgramState::new(Pubkey::new_unique(), treasury);
+        program_state.config.referral_share_bps = 2_500;
+        program_state.config.terms.keeper_bounty_bps = 1_000;
+
+        HashMap::from([
+            (
+                builder::get_program_state_address(&program_id),
+                program_account(borsh::to_vec(&program_state).unwrap()),
+            ),
+            (treasury, program_account(Vec::new())),
+            (
+                builder::get_profile_address(&program_id, sender),
+                program_account(borsh::to_vec(&UserProfile::new(*sender)).unwrap()),
+            ),
+        ])
+    }
+
+    /// Applies `change` to the stored program state, standing in for a timelocked config change
+    fn configure(accounts: &mut HashMap<Pubkey, TestAccount>, change: impl FnOnce(&mut ProgramState)) {
+        let program_state_account = accounts.get_mut(&builder::get_program_state_address(&crate::id())).unwrap();
+        let mut program_state = ProgramState::unpack(&program_state_account.data).unwrap();
+        change(&mut program_state);
+        program_state_account.data = borsh::to_vec(&program_state).unwrap();
+    }
+
+    /// Referral PDA of `referrer` as the first `ClaimReferralRewards` leaves it
+    fn open_referral(accounts: &mut HashMap<Pubkey, TestAccount>, referrer: &Pubkey) -> Pubkey {
+        let referral_pda = builder::get_referral_address(&crate::id(), referrer);
+        let referral = Referral::new(*referrer);
+        accounts.insert(referral_pda, program_account(borsh::to_vec(&referral).unwrap()));
+        referral_pda
+    }
+
+    /// Creates a box staking `STAKE`. The box account and the stake transfer are
+    /// cross-program invocations, so both are done here.
+    fn create(accounts: &mut HashMap<Pubkey, TestAccount>, sender: &Pubkey, id: &Pubkey, options: BoxOptions) -> Pubkey {
+        let box_pda = builder::get_box_address(&crate::id(), sender, id);
+        accounts.insert(box_pda, program_account(vec![0; Box::LEN]));
+
+        process(accounts, &builder::create_box_with_options(&crate::id(), sender, id, 30, STAKE, options)).unwrap();
+
+        accounts.get_mut(&box_pda).unwrap().lamports += STAKE;
+        box_pda
+    }
+
+    fn clock(unix_timestamp: i64) -> Clock {
+        Clock {
+            unix_timestamp,
+            ..Clock::default()
+        }
+    }
+
+    #[test]
+    fn open_builder_matches_the_processor_accounts() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let recipient = Pubkey::new_unique();
+        let relayer = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        let box_pda = create(&mut accounts, &sender, &id, BoxOptions::default());
+
+        // The badge is checked to go to the sender, who is not the recipient here
+        let options = OpenOptions {
+            badge_payer: Some(Pubkey::new_unique()),
+            relayer: Some((relayer, 1_000)),
+            ..OpenOptions::default()
+        };
+        process(&mut accounts, &builder::open_box_with_options(&crate::id(), &sender, &id, &recipient, options)).unwrap();
+
+        assert_eq!(accounts[&recipient].lamports, STAKE - 1_000);
+        assert_eq!(accounts[&relayer].lamports, 1_000);
+        assert_eq!(Box::unpack(&accounts[&box_pda].data).unwrap().deadline, 0);
+
+        let profile = &accounts[&builder::get_profile_address(&crate::id(), &sender)];
+        assert_eq!(UserProfile::unpack(&profile.data).unwrap().boxes_completed, 1);
+    }
+
+    #[test]
+    fn sweep_builders_match_the_processor_accounts() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let referrer = Pubkey::new_unique();
+        let keeper = Pubkey::new_unique();
+        let treasury = builder::get_treasury_address(&crate::id());
+        let mut accounts = setup(&sender);
+        let referral_pda = open_referral(&mut accounts, &referrer);
+        let options = BoxOptions {
+            referrer: Some(referrer),
+            ..BoxOptions::default()
+        };
+        let box_pda = create(&mut accounts, &sender, &id, options);
+
+        let deadline = Box::unpack(&accounts[&box_pda].data).unwrap().deadline;
+        test_stubs::set_clock(clock(deadline));
+        process(&mut accounts, &builder::initiate_sweep(&crate::id(), &sender, &id)).unwrap();
+
+        test_stubs::set_clock(clock(deadline + MIN_DISPUTE_WINDOW));
+        let treasury_lamports = accounts[&treasury].lamports;
+        let referral_lamports = accounts[&referral_pda].lamports;
+        let sweep = builder::finalize_sweep(&crate::id(), &sender, &id, &treasury, &keeper, Some(&referrer));
+        process(&mut accounts, &sweep).unwrap();
+
+        let bounty = STAKE / 10;
+        let share = (STAKE - bounty) / 4;
+        assert_eq!(accounts[&keeper].lamports, bounty);
+        assert_eq!(accounts[&treasury].lamports - treasury_lamports, STAKE - bounty - share);
+        assert_eq!(accounts[&referral_pda].lamports - referral_lamports, share);
+
+        process(&mut accounts, &builder::claim_referral_rewards(&crate::id(), &referrer)).unwrap();
+        assert_eq!(accounts[&referrer].lamports, share);
+        assert_eq!(accounts[&referral_pda].lamports, referral_lamports);
+    }
+
+    #[test]
+    fn unopened_referral_share_stays_with_the_treasury() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let referrer = Pubkey::new_unique();
+        let keeper = Pubkey::new_unique();
+        let treasury = builder::get_treasury_address(&crate::id());
+        let mut accounts = setup(&sender);
+        let options = BoxOptions {
+            referrer: Some(referrer),
+            ..BoxOptions::default()
+        };
+        let box_pda = create(&mut accounts, &sender, &id, options);
+
+        let deadline = Box::unpack(&accounts[&box_pda].data).unwrap().deadline;
+        test_stubs::set_clock(clock(deadline));
+        process(&mut accounts, &builder::initiate_sweep(&crate::id(), &sender, &id)).unwrap();
+
+        test_stubs::set_clock(clock(deadline + MIN_DISPUTE_WINDOW));
+        let treasury_lamports = accounts[&treasury].lamports;
+        test_stubs::take_logged();
+        let sweep = builder::finalize_sweep(&crate::id(), &sender, &id, &treasury, &keeper, Some(&referrer));
+        process(&mut accounts, &sweep).unwrap();
+
+        let bounty = STAKE / 10;
+        let share = (STAKE - bounty) / 4;
+        assert_eq!(accounts[&treasury].lamports - treasury_lamports, STAKE - bounty);
+        let skipped = EscrowEvent::ReferralSkipped(ReferralSkipped {
+            referrer,
+            sender,
+            mint: None,
+            amount: share,
+            timestamp: deadline + MIN_DISPUTE_WINDOW,
+        });
+        let events: Vec<_> = test_stubs::take_logged()
+            .iter()
+            .filter_map(|data| EscrowEvent::unpack(data).ok())
+            .collect();
+        assert!(events.contains(&skipped));
+    }
+
+    #[test]
+    fn arbiter_forfeit_builder_matches_the_processor_accounts() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let arbiter = Pubkey::new_unique();
+        let referrer = Pubkey::new_unique();
+        let treasury = builder::get_treasury_address(&crate::id());
+        let mut accounts = setup(&sender);
+        let referral_pda = open_referral(&mut accounts, &referrer);
+        let options = BoxOptions {
+            arbiter: Some(arbiter),
+            referrer: Some(referrer),
+            ..BoxOptions::default()
+        };
+        create(&mut accounts, &sender, &id, options);
+
+        let treasury_lamports = accounts[&treasury].lamports;
+        let referral_lamports = accounts[&referral_pda].lamports;
+        let forfeit = builder::arbiter_forfeit(&crate::id(), &sender, &id, &arbiter, &treasury, Some(&referrer));
+        process(&mut accounts, &forfeit).unwrap();
+
+        assert_eq!(accounts[&treasury].lamports - treasury_lamports, STAKE - STAKE / 4);
+        assert_eq!(accounts[&referral_pda].lamports - referral_lamports, STAKE / 4);
+    }
+
+    #[test]
+    fn badge_date_handles_leap_years() {
+        assert_eq!(Processor::badge_date(0), "1970-01-01");
+        assert_eq!(Processor::badge_date(951_782_400), "2000-02-29");
+        assert_eq!(Processor::badge_date(1_709_164_800), "2024-02-29");
+        assert_eq!(Processor::badge_date(1_709_164_800 + 86_399), "2024-02-29");
+        assert_eq!(Processor::badge_date(1_677_628_800 - 1), "2023-02-28");
+        assert_eq!(Processor::badge_date(1_677_628_800), "2023-03-01");
+        // Centuries are only leap years every 400 years
+        assert_eq!(Processor::badge_date(4_107_542_400 - 86_400), "2100-02-28");
+        assert_eq!(Processor::badge_date(4_107_542_400), "2100-03-01");
+    }
+
+    #[test]
+    fn badge_date_handles_dates_before_1970() {
+        assert_eq!(Processor::badge_date(-1), "1969-12-31");
+        assert_eq!(Processor::badge_date(-86_400), "1969-12-31");
+        assert_eq!(Processor::badge_date(-86_401), "1969-12-30");
+        assert_eq!(Processor::badge_date(-2_203_891_200), "1900-03-01");
+        assert_eq!(Processor::badge_date(-2_203_891_200 - 1), "1900-02-28");
+        assert_eq!(Processor::badge_date(-11_670_998_400), "1600-02-29");
+    }
+
+    #[test]
+    fn hex_is_lowercase_and_padded() {
+        assert_eq!(Processor::hex(&[]), "");
+        assert_eq!(Processor::hex(&[0x00, 0x0f, 0xa0, 0xff]), "000fa0ff");
+
+        let bytes: Vec<u8> = (0..=255).collect();
+        let expected: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(Processor::hex(&bytes), expected);
+    }
+
+    #[test]
+    fn referral_share_only_comes_from_treasury_forfeits() {
+        let program_id = Pubkey::new_unique();
+        let (treasury, _) = Pubkey::find_program_address(&[b"treasury"], &program_id);
+
+        let mut program_state = ProgramState::new(Pubkey::new_unique(), treasury);
+        program_state.config.referral_share_bps = 2_500;
+
+        assert_eq!(Processor::forfeit_referral_share(&program_id, &program_state, &treasury, 1_000), 250);
+        assert_eq!(Processor::forfeit_referral_share(&program_id, &program_state, &Pubkey::new_unique(), 1_000), 0);
+
+        program_state.config.referral_share_bps = 0;
+        assert_eq!(Processor::forfeit_referral_share(&program_id, &program_state, &treasury, 1_000), 0);
+    }
+
+    #[test]
+    fn migrated_box_ignores_the_configured_terms() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+
+        let box_pda = builder::get_box_address(&crate::id(), &sender, &id);
+        let legacy = LegacyBox {
+            sender,
+            id,
+            deadline: NOW + 86_400,
+            amount: STAKE,
+        };
+        accounts.insert(box_pda, program_account(borsh::to_vec(&legacy).unwrap()));
+
+        process(&mut accounts, &builder::migrate_box(&crate::id(), &payer, &sender, &box_pda)).unwrap();
+
+        // `setup` configures a keeper bounty that the box was never created under
+        let treasury = builder::get_treasury_address(&crate::id());
+        let escrow_box = Box::unpack(&accounts[&box_pda].data).unwrap();
+        assert_eq!(escrow_box.terms, BoxTerms::legacy(treasury));
+        assert_eq!(escrow_box.amount, STAKE);
+    }
+
+    #[test]
+    fn grace_period_opens_need_a_finish_committed_before_the_deadline() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+
+        let program_state_pda = builder::get_program_state_address(&crate::id());
+        let mut program_state = ProgramState::unpack(&accounts[&program_state_pda].data).unwrap();
+        program_state.config.terms.grace_period = 7 * 86_400;
+        accounts.get_mut(&program_state_pda).unwrap().data = borsh::to_vec(&program_state).unwrap();
+
+        create(&mut accounts, &sender, &id, BoxOptions::default());
+        let deadline = NOW + 30 * 86_400;
+        let proof = ReadingProof::Empty;
+        let other_proof = ReadingProof::Checkpoints { codes: vec![[1; 32]] };
+        let open = builder::open_box(&crate::id(), &sender, &id, &sender);
+
+        // Nothing committed: the grace period is no extension
+        test_stubs::set_clock(clock(deadline + 1));
+        assert_eq!(process(&mut accounts, &open), Err(EscrowError::TooLate.into()));
+
+        // Too late to commit once the deadline has passed
+        let commit = builder::commit_finish(&crate::id(), &sender, &id, &id, &proof);
+        assert_eq!(process(&mut accounts, &commit), Err(EscrowError::TooLate.into()));
+
+        test_stubs::set_clock(clock(deadline - 1));
+        process(&mut accounts, &commit).unwrap();
+
+        // Only the committed proof opens the box
+        test_stubs::set_clock(clock(deadline + 1));
+        let options = OpenOptions {
+            proof: other_proof,
+            ..OpenOptions::default()
+        };
+        let open_with_other_proof = builder::open_box_with_options(&crate::id(), &sender, &id, &sender, options);
+        assert_eq!(process(&mut accounts, &open_with_other_proof), Err(EscrowError::TooLate.into()));
+        process(&mut accounts, &open).unwrap();
+    }
+
+    #[test]
+    fn timer_box_opens_once_the_on_chain_timer_counted_the_target() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        let options = BoxOptions {
+            reading_mode: ReadingMode::Timer { target_hours: 1 },
+            ..BoxOptions::default()
+        };
+        create(&mut accounts, &sender, &id, options);
+        let open = builder::open_box(&crate::id(), &sender, &id, &sender);
+        let start = builder::set_timer(&crate::id(), &sender, &id, &id, true);
+        let stop = builder::set_timer(&crate::id(), &sender, &id, &id, false);
+
+        assert_eq!(process(&mut accounts, &open), Err(EscrowError::InvalidReadingProof.into()));
+        assert_eq!(process(&mut accounts, &stop), Err(EscrowError::TimerStopped.into()));
+
+        process(&mut accounts, &start).unwrap();
+        assert_eq!(process(&mut accounts, &start), Err(EscrowError::TimerRunning.into()));
+        test_stubs::set_clock(clock(NOW + 3_599));
+        assert_eq!(process(&mut accounts, &open), Err(EscrowError::TimerRunning.into()));
+        process(&mut accounts, &stop).unwrap();
+        assert_eq!(process(&mut accounts, &open), Err(EscrowError::InvalidReadingProof.into()));
+
+        process(&mut accounts, &start).unwrap();
+        test_stubs::set_clock(clock(NOW + 3_600));
+        process(&mut accounts, &stop).unwrap();
+        process(&mut accounts, &open).unwrap();
+    }
+
+    #[test]
+    fn timer_cannot_run_past_the_deadline() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        let options = BoxOptions {
+            reading_mode: ReadingMode::Timer { target_hours: 1 },
+            ..BoxOptions::default()
+        };
+        create(&mut accounts, &sender, &id, options);
+        process(&mut accounts, &builder::set_timer(&crate::id(), &sender, &id, &id, true)).unwrap();
+
+        test_stubs::set_clock(clock(NOW + 30 * 86_400));
+        let stop = builder::set_timer(&crate::id(), &sender, &id, &id, false);
+        assert_eq!(process(&mut accounts, &stop), Err(EscrowError::TooLate.into()));
+    }
+
+    #[test]
+    fn box_meta_is_not_accepted_as_a_box() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+
+        // A program-owned BoxMeta the size of a box, version byte included
+        let mut meta = BoxMeta::new(Pubkey::new_unique(), sender, BoxMetaFields::default());
+        meta.fields.note = "x".repeat(Box::LEN - meta.space());
+        let data = borsh::to_vec(&meta).unwrap();
+        assert_eq!(data.len(), Box::LEN);
+        assert_eq!(data[1], Box::VERSION);
+
+        let fake_box = Pubkey::new_unique();
+        accounts.insert(fake_box, program_account(data));
+
+        let mut open = builder::open_box(&crate::id(), &sender, &id, &sender);
+        open.accounts[0].pubkey = fake_box;
+        assert_eq!(process(&mut accounts, &open), Err(ProgramError::InvalidAccountData));
+
+        let set_meta = builder::set_box_meta(&crate::id(), &sender, &fake_box, BoxMetaFields::default());
+        assert_eq!(process(&mut accounts, &set_meta), Err(ProgramError::InvalidAccountData));
+
+        let close_meta = builder::close_box_meta(&crate::id(), &sender, &fake_box);
+        assert_eq!(process(&mut accounts, &close_meta), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn create_boxes_creates_every_entry() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+
+        let entries: Vec<_> = (1..=3)
+            .map(|days| BoxEntry {
+                id: Pubkey::new_unique(),
+                deadline_days: days,
+                amount: STAKE,
+                options: BoxOptions::default(),
+            })
+            .collect();
+        for entry in &entries {
+            let box_pda = builder::get_box_address(&crate::id(), &sender, &entry.id);
+            accounts.insert(box_pda, program_account(vec![0; Box::LEN]));
+        }
+
+        process(&mut accounts, &builder::create_boxes(&crate::id(), &sender, entries.clone())).unwrap();
+
+        for entry in &entries {
+            let box_pda = builder::get_box_address(&crate::id(), &sender, &entry.id);
+            let escrow_box = Box::unpack(&accounts[&box_pda].data).unwrap();
+            assert_eq!(escrow_box.id, entry.id);
+            assert_eq!(escrow_box.deadline, NOW + i64::from(entry.deadline_days) * 86_400);
+        }
+
+        let profile = &accounts[&builder::get_profile_address(&crate::id(), &sender)];
+        assert_eq!(UserProfile::unpack(&profile.data).unwrap().boxes_created, 3);
+    }
+
+    #[test]
+    fn create_boxes_rejects_an_oversized_batch() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+
+        let entry = BoxEntry {
+            id: Pubkey::new_unique(),
+            deadline_days: 30,
+            amount: STAKE,
+            options: BoxOptions::default(),
+        };
+        let create = builder::create_boxes(&crate::id(), &sender, vec![entry; MAX_BATCH_BOXES + 1]);
+        assert_eq!(process(&mut accounts, &create), Err(EscrowError::TooManyBoxes.into()));
+
+        let empty = builder::create_boxes(&crate::id(), &sender, Vec::new());
+        assert_eq!(process(&mut accounts, &empty), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn program_state_counts_boxes_through_their_lifetime() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let opened_id = Pubkey::new_unique();
+        let swept_id = Pubkey::new_unique();
+        let treasury = builder::get_treasury_address(&crate::id());
+        let program_state_pda = builder::get_program_state_address(&crate::id());
+        let mut accounts = setup(&sender);
+        create(&mut accounts, &sender, &opened_id, BoxOptions::default());
+        let swept_pda = create(&mut accounts, &sender, &swept_id, BoxOptions::default());
+
+        let program_state = ProgramState::unpack(&accounts[&program_state_pda].data).unwrap();
+        assert_eq!(program_state.active_boxes, 2);
+        assert_eq!(program_state.total_locked_lamports, 2 * STAKE);
+        assert_eq!(program_state.total_boxes_created, 2);
+
+        process(&mut accounts, &builder::open_box(&crate::id(), &sender, &opened_id, &sender)).unwrap();
+
+        let deadline = Box::unpack(&accounts[&swept_pda].data).unwrap().deadline;
+        test_stubs::set_clock(clock(deadline));
+        process(&mut accounts, &builder::initiate_sweep(&crate::id(), &sender, &swept_id)).unwrap();
+        test_stubs::set_clock(clock(deadline + MIN_DISPUTE_WINDOW));
+        let keeper = Pubkey::new_unique();
+        process(&mut accounts, &builder::finalize_sweep(&crate::id(), &sender, &swept_id, &treasury, &keeper, None)).unwrap();
+
+        let program_state = ProgramState::unpack(&accounts[&program_state_pda].data).unwrap();
+        assert_eq!(program_state.active_boxes, 0);
+        assert_eq!(program_state.total_locked_lamports, 0);
+        assert_eq!(program_state.total_opened, 1);
+        assert_eq!(program_state.total_swept, 1);
+        assert_eq!(program_state.total_boxes_created, 2);
+    }
+
+    #[test]
+    fn sol_stakes_outside_the_limits_are_rejected() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        configure(&mut accounts, |program_state| {
+            program_state.config.sol_limits = StakeLimits { min: STAKE, max: 2 * STAKE };
+        });
+
+        for (amount, error) in [
+            (0, EscrowError::ZeroAmount),
+            (STAKE - 1, EscrowError::StakeTooSmall),
+            (2 * STAKE + 1, EscrowError::StakeTooLarge),
+        ] {
+            let create = builder::create_box(&crate::id(), &sender, &id, 30, amount);
+            assert_eq!(process(&mut accounts, &create), Err(error.into()));
+        }
+
+        create(&mut accounts, &sender, &id, BoxOptions::default());
+    }
+
+    #[test]
+    fn token_boxes_need_an_allowed_spl_token_mint() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        let program_state = &accounts[&builder::get_program_state_address(&crate::id())];
+        let authority = ProgramState::unpack(&program_state.data).unwrap().config.authority;
+
+        let mint_state = spl_token::state::Mint {
+            decimals: 6,
+            is_initialized: true,
+            freeze_authority: Some(Pubkey::new_unique()).into(),
+            ..spl_token::state::Mint::default()
+        };
+        let mut mint_data = vec![0; spl_token::state::Mint::LEN];
+        spl_token::state::Mint::pack(mint_state, &mut mint_data).unwrap();
+        let mint_account = TestAccount {
+            lamports: Rent::default().minimum_balance(mint_data.len()),
+            data: mint_data,
+            owner: spl_token::id(),
+        };
+        accounts.insert(mint, mint_account.clone());
+
+        let create = builder::create_box_token(&crate::id(), &sender, &mint, &id, 30, STAKE);
+        assert_eq!(process(&mut accounts, &create), Err(EscrowError::MintNotAllowed.into()));
+
+        let mint_config_pda = builder::get_mint_config_address(&crate::id(), &mint);
+        accounts.insert(mint_config_pda, program_account(vec![0; MintConfig::LEN]));
+        let add = builder::add_mint(&crate::id(), &authority, &[], &mint, 1, 0, false);
+        assert_eq!(process(&mut accounts, &add), Err(EscrowError::FreezableMint.into()));
+
+        let add = builder::add_mint(&crate::id(), &authority, &[], &mint, 1, 0, true);
+        process(&mut accounts, &add).unwrap();
+        let mint_config = MintConfig::unpack(&accounts[&mint_config_pda].data).unwrap();
+        assert_eq!(mint_config.decimals, 6);
+        assert_eq!(mint_config.limits, StakeLimits { min: 1, max: 0 });
+        assert!(mint_config.allow_freeze_authority);
+
+        // A look-alike mint owned by another program is refused even though it is allowed
+        accounts.insert(mint, TestAccount { owner: Pubkey::new_unique(), ..mint_account });
+        assert_eq!(process(&mut accounts, &create), Err(EscrowError::InvalidMint.into()));
+    }
+
+    #[test]
+    fn signer_set_needs_the_threshold_of_distinct_members() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        let members = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut authority = Pubkey::default();
+        configure(&mut accounts, |program_state| {
+            authority = program_state.config.authority;
+            program_state.config.signers[..3].copy_from_slice(&members);
+            program_state.config.signer_count = 3;
+            program_state.config.threshold = 2;
+        });
+
+        let outsider = Pubkey::new_unique();
+        for signers in [
+            vec![],
+            vec![&members[0]],
+            vec![&members[0], &members[0]],
+            vec![&members[0], &outsider],
+        ] {
+            let pause = builder::set_paused(&crate::id(), &authority, &signers, true, false, false);
+            assert_eq!(process(&mut accounts, &pause), Err(ProgramError::MissingRequiredSignature));
+        }
+
+        let pause = builder::set_paused(&crate::id(), &authority, &[&members[0], &members[2]], true, false, false);
+        process(&mut accounts, &pause).unwrap();
+        let program_state = &accounts[&builder::get_program_state_address(&crate::id())];
+        assert!(ProgramState::unpack(&program_state.data).unwrap().paused_create);
+    }
+
+    #[test]
+    fn queued_config_waits_for_the_timelock_and_spares_existing_boxes() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let program_state_pda = builder::get_program_state_address(&crate::id());
+        let mut accounts = setup(&sender);
+        let box_pda = create(&mut accounts, &sender, &id, BoxOptions::default());
+
+        let mut config = ProgramState::unpack(&accounts[&program_state_pda].data).unwrap().config;
+        let old_terms = config.terms;
+        config.terms.keeper_bounty_bps = 500;
+
+        // `QueueConfig` creates the account through the system program, so it is stored here
+        let pending_config_pda = builder::get_pending_config_address(&crate::id());
+        let pending_config = PendingConfig::new(config.clone(), NOW + CONFIG_TIMELOCK, payer);
+        accounts.insert(pending_config_pda, program_account(borsh::to_vec(&pending_config).unwrap()));
+
+        let queue = builder::queue_config(&crate::id(), &config.authority, &[], &payer, config.clone());
+        assert_eq!(process(&mut accounts, &queue), Err(EscrowError::ConfigPending.into()));
+
+        test_stubs::set_clock(clock(NOW + CONFIG_TIMELOCK - 1));
+        let execute = builder::execute_config(&crate::id(), &payer);
+        assert_eq!(process(&mut accounts, &execute), Err(EscrowError::TimelockActive.into()));
+
+        test_stubs::set_clock(clock(NOW + CONFIG_TIMELOCK));
+        let rent = accounts[&pending_config_pda].lamports;
+        process(&mut accounts, &execute).unwrap();
+
+        assert_eq!(ProgramState::unpack(&accounts[&program_state_pda].data).unwrap().config, config);
+        assert_eq!(accounts[&payer].lamports, rent);
+        assert_eq!(accounts[&pending_config_pda].lamports, 0);
+        assert!(accounts[&pending_config_pda].data.iter().all(|byte| *byte == 0));
+        assert_eq!(Box::unpack(&accounts[&box_pda].data).unwrap().terms, old_terms);
+    }
+
+    #[test]
+    fn treasury_withdrawals_need_the_authority() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let treasury = builder::get_treasury_address(&crate::id());
+        let mut accounts = setup(&sender);
+        accounts.get_mut(&treasury).unwrap().lamports += STAKE;
+        let program_state = &accounts[&builder::get_program_state_address(&crate::id())];
+        let authority = ProgramState::unpack(&program_state.data).unwrap().config.authority;
+
+        let withdraw = builder::withdraw_treasury(&crate::id(), &sender, &[], &destination, STAKE);
+        assert_eq!(process(&mut accounts, &withdraw), Err(EscrowError::Unauthorized.into()));
+
+        // The treasury stays rent exempt
+        let withdraw = builder::withdraw_treasury(&crate::id(), &authority, &[], &destination, STAKE + 1);
+        assert_eq!(process(&mut accounts, &withdraw), Err(EscrowError::InsufficientTreasury.into()));
+
+        let withdraw = builder::withdraw_treasury(&crate::id(), &authority, &[], &destination, STAKE);
+        process(&mut accounts, &withdraw).unwrap();
+        assert_eq!(accounts[&destination].lamports, STAKE);
+        assert_eq!(accounts[&treasury].lamports, Rent::default().minimum_balance(0));
+
+        // The treasury PDA signs token withdrawals, so they may only reach SPL Token
+        let mint = Pubkey::new_unique();
+        let mut withdraw = builder::withdraw_treasury_token(&crate::id(), &authority, &[], &mint, &destination, 1);
+        withdraw.accounts[5].pubkey = Pubkey::new_unique();
+        assert_eq!(process(&mut accounts, &withdraw), Err(ProgramError::IncorrectProgramId));
+    }
+
+    #[test]
+    fn any_keeper_sweeps_an_expired_box_for_the_bounty() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let keeper = Pubkey::new_unique();
+        let treasury = builder::get_treasury_address(&crate::id());
+        let mut accounts = setup(&sender);
+        let box_pda = create(&mut accounts, &sender, &id, BoxOptions::default());
+        let deadline = Box::unpack(&accounts[&box_pda].data).unwrap().deadline;
+        let initiate = builder::initiate_sweep(&crate::id(), &sender, &id);
+        let finalize = builder::finalize_sweep(&crate::id(), &sender, &id, &treasury, &keeper, None);
+
+        test_stubs::set_clock(clock(deadline - 1));
+        assert_eq!(process(&mut accounts, &initiate), Err(EscrowError::NotExpired.into()));
+        assert_eq!(process(&mut accounts, &finalize), Err(EscrowError::SweepNotInitiated.into()));
+
+        test_stubs::set_clock(clock(deadline));
+        process(&mut accounts, &initiate).unwrap();
+        assert_eq!(process(&mut accounts, &initiate), Err(EscrowError::SweepAlreadyInitiated.into()));
+
+        test_stubs::set_clock(clock(deadline + MIN_DISPUTE_WINDOW - 1));
+        assert_eq!(process(&mut accounts, &finalize), Err(EscrowError::DisputeWindowOpen.into()));
+
+        test_stubs::set_clock(clock(deadline + MIN_DISPUTE_WINDOW));
+        let elsewhere = builder::finalize_sweep(&crate::id(), &sender, &id, &keeper, &keeper, None);
+        assert_eq!(process(&mut accounts, &elsewhere), Err(EscrowError::Unauthorized.into()));
+
+        let treasury_lamports = accounts[&treasury].lamports;
+        process(&mut accounts, &finalize).unwrap();
+        assert_eq!(accounts[&keeper].lamports, STAKE / 10);
+        assert_eq!(accounts[&treasury].lamports - treasury_lamports, STAKE - STAKE / 10);
+        assert_eq!(process(&mut accounts, &finalize), Err(EscrowError::UnknownId.into()));
+    }
+
+    #[test]
+    fn only_the_named_arbiter_releases_before_the_deadline() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let arbiter = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        let options = BoxOptions {
+            arbiter: Some(arbiter),
+            ..BoxOptions::default()
+        };
+        let box_pda = create(&mut accounts, &sender, &id, options);
+        let deadline = Box::unpack(&accounts[&box_pda].data).unwrap().deadline;
+
+        let impostor = builder::arbiter_release(&crate::id(), &sender, &id, &Pubkey::new_unique(), &sender);
+        assert_eq!(process(&mut accounts, &impostor), Err(EscrowError::Unauthorized.into()));
+
+        let elsewhere = builder::arbiter_release(&crate::id(), &sender, &id, &arbiter, &arbiter);
+        assert_eq!(process(&mut accounts, &elsewhere), Err(EscrowError::Unauthorized.into()));
+
+        let release = builder::arbiter_release(&crate::id(), &sender, &id, &arbiter, &sender);
+        test_stubs::set_clock(clock(deadline));
+        assert_eq!(process(&mut accounts, &release), Err(EscrowError::TooLate.into()));
+
+        test_stubs::set_clock(clock(deadline - 1));
+        process(&mut accounts, &release).unwrap();
+        assert_eq!(accounts[&sender].lamports, STAKE);
+        assert_eq!(Box::unpack(&accounts[&box_pda].data).unwrap().deadline, 0);
+    }
+
+    #[test]
+    fn rotated_box_opens_with_the_new_id_only() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let new_id = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        let box_pda = create(&mut accounts, &sender, &id, BoxOptions::default());
+        let deadline = Box::unpack(&accounts[&box_pda].data).unwrap().deadline;
+
+        // Only the sender's wallet rotates; the lost id key cannot
+        let mut rotate = builder::rotate_box_id(&crate::id(), &sender, &id, &new_id);
+        rotate.accounts[1].pubkey = id;
+        assert_eq!(process(&mut accounts, &rotate), Err(EscrowError::Unauthorized.into()));
+
+        let rotate = builder::rotate_box_id(&crate::id(), &sender, &id, &Pubkey::default());
+        assert_eq!(process(&mut accounts, &rotate), Err(ProgramError::InvalidArgument));
+
+        let rotate = builder::rotate_box_id(&crate::id(), &sender, &id, &new_id);
+        test_stubs::set_clock(clock(deadline));
+        assert_eq!(process(&mut accounts, &rotate), Err(EscrowError::TooLate.into()));
+
+        test_stubs::set_clock(clock(deadline - 1));
+        process(&mut accounts, &rotate).unwrap();
+        assert_eq!(Box::unpack(&accounts[&box_pda].data).unwrap().rotated_id, new_id);
+
+        let open = builder::open_box(&crate::id(), &sender, &id, &sender);
+        assert_eq!(process(&mut accounts, &open), Err(ProgramError::MissingRequiredSignature));
+
+        let options = OpenOptions {
+            id_key: Some(new_id),
+            ..OpenOptions::default()
+        };
+        process(&mut accounts, &builder::open_box_with_options(&crate::id(), &sender, &id, &sender, options)).unwrap();
+        assert_eq!(accounts[&sender].lamports, STAKE);
+    }
+
+    #[test]
+    fn guardian_recovery_waits_for_the_delay() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let new_id = Pubkey::new_unique();
+        let payout = Pubkey::new_unique();
+        let guardians = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut accounts = setup(&sender);
+        let options = BoxOptions {
+            guardians: guardians.to_vec(),
+            guardian_threshold: 2,
+            ..BoxOptions::default()
+        };
+        create(&mut accounts, &sender, &id, options);
+
+        let recover = builder::recover_box(&crate::id(), &sender, &id, &[&guardians[0]], &new_id, &payout);
+        assert_eq!(process(&mut accounts, &recover), Err(ProgramError::MissingRequiredSignature));
+
+        let approvals = [&guardians[0], &guardians[2]];
+        process(&mut accounts, &builder::recover_box(&crate::id(), &sender, &id, &approvals, &new_id, &payout)).unwrap();
+
+        let open_with = |recipient: &Pubkey| {
+            let options = OpenOptions {
+                id_key: Some(new_id),
+                payout: Some(payout),
+                ..OpenOptions::default()
+            };
+            builder::open_box_with_options(&crate::id(), &sender, &id, recipient, options)
+        };
+
+        test_stubs::set_clock(clock(NOW + RECOVERY_DELAY - 1));
+        assert_eq!(process(&mut accounts, &open_with(&payout)), Err(ProgramError::MissingRequiredSignature));
+
+        test_stubs::set_clock(clock(NOW + RECOVERY_DELAY));
+        assert_eq!(process(&mut accounts, &open_with(&sender)), Err(EscrowError::Unauthorized.into()));
+        process(&mut accounts, &open_with(&payout)).unwrap();
+        assert_eq!(accounts[&payout].lamports, STAKE);
+    }
+
+    #[test]
+    fn box_records_the_book_it_is_bound_to() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+
+        let options = BoxOptions {
+            title: Some("x".repeat(MAX_TITLE_LEN + 1)),
+            ..BoxOptions::default()
+        };
+        let create_box = builder::create_box_with_options(&crate::id(), &sender, &id, 30, STAKE, options);
+        assert_eq!(process(&mut accounts, &create_box), Err(EscrowError::TitleTooLong.into()));
+
+        let options = BoxOptions {
+            content_hash: Some([7; 32]),
+            title: Some("Middlemarch".to_string()),
+            ..BoxOptions::default()
+        };
+        test_stubs::take_logged();
+        let box_pda = create(&mut accounts, &sender, &id, options);
+
+        let book = Box::unpack(&accounts[&box_pda].data).unwrap().book;
+        assert_eq!(book.content_hash, [7; 32]);
+        assert_eq!(book.title(), "Middlemarch");
+        let created = test_stubs::take_logged()
+            .iter()
+            .find_map(|data| match EscrowEvent::unpack(data) {
+                Ok(EscrowEvent::BoxCreated(created)) => Some(created),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(created.content_hash, [7; 32]);
+    }
+
+    #[test]
+    fn box_meta_is_edited_by_the_sender_and_closed_with_the_box() {
+        test_stubs::install(clock(NOW));
+        let sender = Pubkey::new_unique();
+        let id = Pubkey::new_unique();
+        let mut accounts = setup(&sender);
+        let box_pda = create(&mut accounts, &sender, &id, BoxOptions::default());
+
+        // As `CreateBox` leaves it when created with labels
+        let meta_pda = builder::get_box_meta_address(&crate::id(), &box_pda);
+        let fields = BoxMetaFields {
+            title: "Middlemarch".to_string(),
+            author: "George Eliot".to_string(),
+            note: "Book club, March".to_string(),
+            language: "en".to_string(),
+        };
+        let meta = BoxMeta::new(box_pda, sender, fields);
+        accounts.insert(meta_pda, program_account(borsh::to_vec(&meta).unwrap()));
+
+        let too_long = BoxMetaFields {
+            note: "x".repeat(MAX_META_NOTE_LEN + 1),
+            ..BoxMetaFields::default()
+        };
+        let set_meta = builder::set_box_meta(&crate::id(), &sender, &box_pda, too_long);
+        assert_eq!(process(&mut accounts, &set_meta), Err(EscrowError::MetaTooLong.into()));
+
+        let fields = BoxMetaFields {
+            title: "Middlemarch".to_string(),
+            ..BoxMetaFields::default()
+        };
+        let set_meta = builder::set_box_meta(&crate::id(), &Pubkey::new_unique(), &box_pda, fields.clone());
+        assert_eq!(process(&mut accounts, &set_meta), Err(EscrowError::Unauthorized.into()));
+
+        // Shorter labels shrink the account and hand the freed rent back
+        process(&mut accounts, &builder::set_box_meta(&crate::id(), &sender, &box_pda, fields.clone())).unwrap();
+        let meta = BoxMeta::unpack(&accounts[&meta_pda].data).unwrap();
+        assert_eq!(meta.fields, fields);
+        assert_eq!(accounts[&meta_pda].data.len(), meta.space());
+        assert_eq!(accounts[&meta_pda].lamports, Rent::default().minimum_balance(meta.space()));
+
+        let close_meta = builder::close_box_meta(&crate::id(), &sender, &box_pda);
+        assert_eq!(process(&mut accounts, &close_meta), Err(EscrowError::BoxStillActive.into()));
+
+        process(&mut accounts, &builder::open_box(&crate::id(), &sender, &id, &sender)).unwrap();
+        let sender_lamports = accounts[&sender].lamports;
+        let rent = accounts[&meta_pda].lamports;
+        process(&mut accounts, &close_meta).unwrap();
+
+        assert_eq!(accounts[&sender].lamports - sender_lamports, rent);
+        assert_eq!(accounts[&meta_pda].lamports, 0);
+        assert!(accounts[&meta_pda].data.iter().all(|byte| *byte == 0));
+    }
+}
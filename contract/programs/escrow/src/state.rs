@@ -1,34 +1,1313 @@
-use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::pubkey::Pubkey;
+use borsh::{
+    io::{Error, ErrorKind, Read, Write},
+    BorshDeserialize, BorshSerialize,
+};
+use solana_program::{
+    clock::{Clock, DEFAULT_MS_PER_SLOT, DEFAULT_SLOTS_PER_EPOCH},
+    hash::hashv,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use crate::error::EscrowError;
+
+/// Allowed stake range; a `max` of 0 means no upper limit
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct StakeLimits {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl StakeLimits {
+    pub const LEN: usize = 8 + 8; // min + max
+
+    pub fn is_valid(&self) -> bool {
+        self.max == 0 || self.min <= self.max
+    }
+
+    pub fn check(&self, amount: u64) -> Result<(), ProgramError> {
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        if amount < self.min {
+            return Err(EscrowError::StakeTooSmall.into());
+        }
+
+        if self.max != 0 && amount > self.max {
+            return Err(EscrowError::StakeTooLarge.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Maximum number of keys in the authority signer set
+pub const MAX_SIGNERS: usize = 5;
+
+/// Shortest dispute window a config may set, so a sweep can never be initiated and
+/// finalized in the same transaction
+pub const MIN_DISPUTE_WINDOW: i64 = 86400;
+
+/// Minimum delay between queueing a config change and executing it. `SetPaused`, `AddMint`
+/// and `RemoveMint` are not timelocked as none of them can move a deposited stake or
+/// shorten a deadline.
+pub const CONFIG_TIMELOCK: i64 = 2 * 86400;
+
+/// Largest keeper bounty a config may set (10%)
+pub const MAX_KEEPER_BOUNTY_BPS: u16 = 1_000;
+
+/// Upper bound of the fee a relayer can take from an opened box
+pub const MAX_RELAYER_FEE_BPS: u16 = 1_000;
+
+/// Relayer fee cap of a new config (1%). Nonzero so that boxes can be opened through a
+/// relayer, which refuses to relay for free.
+pub const DEFAULT_RELAYER_FEE_BPS: u16 = 100;
+
+/// Maximum number of guardians a box can register
+pub const MAX_GUARDIANS: usize = 5;
+
+/// Delay between guardians approving a recovery and it taking effect
+pub const RECOVERY_DELAY: i64 = 3 * 86400;
+
+/// Keys that can jointly recover a box whose sender wallet is lost
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct GuardianSet {
+    pub guardians: [Pubkey; MAX_GUARDIANS], // first `count` entries are used
+    pub count: u8,
+    pub threshold: u8,
+}
+
+impl GuardianSet {
+    pub const LEN: usize = 32 * MAX_GUARDIANS + 1 + 1; // guardians + count + threshold
+
+    pub fn new(guardians: &[Pubkey], threshold: u8) -> Result<Self, ProgramError> {
+        if guardians.len() > MAX_GUARDIANS
+            || threshold as usize > guardians.len()
+            || (threshold == 0) != guardians.is_empty()
+        {
+            return Err(EscrowError::InvalidGuardianSet.into());
+        }
+
+        for (i, guardian) in guardians.iter().enumerate() {
+            if *guardian == Pubkey::default() || guardians[..i].contains(guardian) {
+                return Err(EscrowError::InvalidGuardianSet.into());
+            }
+        }
+
+        let mut set = Self {
+            count: guardians.len() as u8,
+            threshold,
+            ..Self::default()
+        };
+        set.guardians[..guardians.len()].copy_from_slice(guardians);
+        Ok(set)
+    }
+
+    pub fn members(&self) -> &[Pubkey] {
+        &self.guardians[..self.count as usize]
+    }
+}
+
+/// Guardian-approved replacement of a box's key and payout address
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Recovery {
+    pub new_id: Pubkey,
+    pub payout: Pubkey,
+    pub effective_at: i64, // 0 = no recovery queued
+}
+
+impl Recovery {
+    pub const LEN: usize = 32 + 32 + 8; // new id + payout + effective at
+
+    pub fn is_effective(&self, now: i64) -> bool {
+        self.effective_at != 0 && now >= self.effective_at
+    }
+}
+
+/// Reading proof the box id key committed to with `CommitFinish` while the deadline had not
+/// passed; during the grace period the box can only be opened with that proof
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct FinishCommitment {
+    pub proof_hash: [u8; 32], // `ReadingProof::hash` of the proof
+    pub committed_at: i64, // in the units of the box's deadline kind, 0 = nothing committed
+}
+
+impl FinishCommitment {
+    pub const LEN: usize = 32 + 8; // proof hash + committed at
+
+    /// Whether a proof hashing to `proof_hash` was committed before `effective_deadline`
+    pub fn covers(&self, proof_hash: &[u8; 32], effective_deadline: i64) -> bool {
+        self.committed_at != 0 && self.committed_at < effective_deadline && self.proof_hash == *proof_hash
+    }
+}
+
+/// Reading time of a `ReadingMode::Timer` box, counted between `SetTimer` calls made before
+/// the deadline
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReadingTimer {
+    pub started_at: i64, // unix time the running timer was started, 0 = stopped
+    pub elapsed: i64, // seconds counted by the runs already stopped
+}
+
+impl ReadingTimer {
+    pub const LEN: usize = 8 + 8; // started at + elapsed
+
+    pub fn is_running(&self) -> bool {
+        self.started_at != 0
+    }
+
+    pub fn start(&mut self, now: i64) -> Result<(), ProgramError> {
+        if self.is_running() {
+            return Err(EscrowError::TimerRunning.into());
+        }
+        self.started_at = now;
+        Ok(())
+    }
+
+    pub fn stop(&mut self, now: i64) -> Result<(), ProgramError> {
+        if !self.is_running() {
+            return Err(EscrowError::TimerStopped.into());
+        }
+        self.elapsed = self.elapsed.saturating_add(now.saturating_sub(self.started_at));
+        self.started_at = 0;
+        Ok(())
+    }
+}
+
+/// Maximum length of a box's book title in bytes (UTF-8)
+pub const MAX_TITLE_LEN: usize = 64;
+
+/// Book a box is committed to
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BookRef {
+    pub content_hash: [u8; 32], // SHA-256 of the EPUB/PDF file, all zero = not bound
+    pub title: [u8; MAX_TITLE_LEN], // first `title_len` bytes are used
+    pub title_len: u8,
+}
+
+impl Default for BookRef {
+    fn default() -> Self {
+        Self {
+            content_hash: [0; 32],
+            title: [0; MAX_TITLE_LEN],
+            title_len: 0,
+        }
+    }
+}
+
+impl BookRef {
+    pub const LEN: usize = 32 + MAX_TITLE_LEN + 1; // content hash + title + title length
+
+    pub fn new(content_hash: [u8; 32], title: &str) -> Result<Self, ProgramError> {
+        if title.len() > MAX_TITLE_LEN {
+            return Err(EscrowError::TitleTooLong.into());
+        }
+
+        let mut book = Self {
+            content_hash,
+            title_len: title.len() as u8,
+            ..Self::default()
+        };
+        book.title[..title.len()].copy_from_slice(title.as_bytes());
+        Ok(book)
+    }
+
+    pub fn title(&self) -> &str {
+        std::str::from_utf8(&self.title[..self.title_len as usize]).unwrap_or_default()
+    }
+}
+
+/// Clock a box deadline is measured against. Durations (`deadline_days`, grace period, pause
+/// time) are converted with the nominal slot time, so slot and epoch deadlines are exact in
+/// ledger terms but only approximate in wall-clock terms. Epochs are coarse: a nominal epoch
+/// lasts about two days, and every span is rounded up to whole epochs.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum DeadlineKind {
+    /// `Clock::unix_timestamp`, in seconds
+    #[default]
+    UnixTimestamp,
+    /// `Clock::slot`
+    Slot,
+    /// `Clock::epoch`
+    Epoch,
+}
+
+impl DeadlineKind {
+    pub const LEN: usize = 1;
+
+    const SECONDS_PER_EPOCH: i64 = (DEFAULT_SLOTS_PER_EPOCH * DEFAULT_MS_PER_SLOT / 1_000) as i64;
+
+    /// Current time in this kind's units
+    pub fn now(&self, clock: &Clock) -> i64 {
+        match self {
+            Self::UnixTimestamp => clock.unix_timestamp,
+            Self::Slot => clock.slot as i64,
+            Self::Epoch => clock.epoch as i64,
+        }
+    }
+
+    /// Unix time at which the current unit (`now`) began. Only an epoch is long enough for
+    /// the difference to matter; it is measured from `Clock::epoch_start_timestamp`.
+    fn unit_start(&self, clock: &Clock) -> i64 {
+        match self {
+            Self::UnixTimestamp | Self::Slot => clock.unix_timestamp,
+            Self::Epoch => clock.epoch_start_timestamp.min(clock.unix_timestamp),
+        }
+    }
+
+    /// Length of `seconds` in this kind's units, rounded up (a one-day span is a whole epoch)
+    pub fn span(&self, seconds: i64) -> i64 {
+        match self {
+            Self::UnixTimestamp => seconds,
+            Self::Slot => {
+                let ms_per_slot = DEFAULT_MS_PER_SLOT as i64;
+                seconds.saturating_mul(1_000).saturating_add(ms_per_slot - 1) / ms_per_slot
+            }
+            Self::Epoch => seconds.saturating_add(Self::SECONDS_PER_EPOCH - 1) / Self::SECONDS_PER_EPOCH,
+        }
+    }
+
+    /// Nominal length of `units` of this kind, in seconds
+    pub fn seconds(&self, units: i64) -> i64 {
+        match self {
+            Self::UnixTimestamp => units,
+            Self::Slot => units.saturating_mul(DEFAULT_MS_PER_SLOT as i64) / 1_000,
+            Self::Epoch => units.saturating_mul(Self::SECONDS_PER_EPOCH),
+        }
+    }
+
+    /// Deadline `days` from now, in this kind's units. An epoch deadline is the first epoch
+    /// nominally starting at least `days` from now, so it can run up to an epoch longer.
+    pub fn deadline_after(&self, clock: &Clock, days: u16) -> i64 {
+        let elapsed = clock.unix_timestamp - self.unit_start(clock);
+        self.now(clock).saturating_add(self.span(elapsed.saturating_add(days as i64 * 86400)))
+    }
+
+    /// Estimated unix time at which a deadline of this kind is reached, given a recent `clock`
+    #[cfg(not(target_os = "solana"))]
+    pub fn to_unix_estimate(&self, deadline: i64, clock: &Clock) -> i64 {
+        let remaining = deadline.saturating_sub(self.now(clock));
+        self.unit_start(clock).saturating_add(self.seconds(remaining))
+    }
+
+    /// Earliest deadline of this kind estimated to be reached at or after `unix_time`,
+    /// given a recent `clock`
+    #[cfg(not(target_os = "solana"))]
+    pub fn from_unix_estimate(&self, unix_time: i64, clock: &Clock) -> i64 {
+        let remaining = unix_time.saturating_sub(self.unit_start(clock));
+        self.now(clock).saturating_add(self.span(remaining))
+    }
+}
+
+/// Maximum number of checkpoints of a `ReadingMode::Checkpoints` box
+pub const MAX_CHECKPOINTS: u8 = 16;
+
+/// How a box's reading is verified when it is opened
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ReadingMode {
+    /// No mode-specific check
+    #[default]
+    Unspecified,
+    /// `count` checkpoint codes are hidden in the book; opening reveals them and the SHA-256
+    /// of the codes, concatenated in order, must equal `commitment_root`
+    Checkpoints { count: u8, commitment_root: [u8; 32] },
+    /// Reading timer run on chain with `SetTimer`; opening needs the timer stopped with at least
+    /// `target_hours` counted. The timer proves the id key holder kept it running that long
+    /// before the deadline, not that the time was spent reading. Use `Checkpoints`, or an
+    /// attestor appeal, where the reading has to be shown.
+    Timer { target_hours: u16 },
+}
+
+impl ReadingMode {
+    pub const LEN: usize = 1 + 1 + 32; // tag + largest variant (count + commitment root)
+
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Self::Unspecified => true,
+            Self::Checkpoints { count, .. } => count > 0 && count <= MAX_CHECKPOINTS,
+            Self::Timer { target_hours } => target_hours > 0,
+        }
+    }
+
+    /// Checks revealed checkpoint codes against a `Checkpoints` commitment
+    pub fn verify_checkpoints(&self, codes: &[[u8; 32]]) -> bool {
+        match *self {
+            Self::Checkpoints { count, commitment_root } => {
+                let codes: Vec<&[u8]> = codes.iter().map(|code| code.as_slice()).collect();
+                codes.len() == count as usize && hashv(&codes).to_bytes() == commitment_root
+            }
+            _ => false,
+        }
+    }
+}
+
+// Always `LEN` bytes (zero padded) so boxes keep a fixed size whatever the mode
+impl BorshSerialize for ReadingMode {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let mut data = [0u8; Self::LEN];
+        match *self {
+            Self::Unspecified => {}
+            Self::Checkpoints { count, commitment_root } => {
+                data[0] = 1;
+                data[1] = count;
+                data[2..].copy_from_slice(&commitment_root);
+            }
+            Self::Timer { target_hours } => {
+                data[0] = 2;
+                data[1..3].copy_from_slice(&target_hours.to_le_bytes());
+            }
+        }
+        writer.write_all(&data)
+    }
+}
+
+impl BorshDeserialize for ReadingMode {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut data = [0u8; Self::LEN];
+        reader.read_exact(&mut data)?;
+        match data[0] {
+            0 => Ok(Self::Unspecified),
+            1 => {
+                let mut commitment_root = [0u8; 32];
+                commitment_root.copy_from_slice(&data[2..]);
+                Ok(Self::Checkpoints {
+                    count: data[1],
+                    commitment_root,
+                })
+            }
+            2 => Ok(Self::Timer {
+                target_hours: u16::from_le_bytes([data[1], data[2]]),
+            }),
+            _ => Err(Error::new(ErrorKind::InvalidData, "invalid reading mode")),
+        }
+    }
+}
+
+/// Settlement terms a box is created under; copied into every box so later
+/// config changes never apply to it retroactively
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BoxTerms {
+    pub beneficiary: Pubkey, // receives forfeited stakes, the treasury PDA by default
+    pub keeper_bounty_bps: u16, // share of a forfeited stake paid to whoever sweeps it
+    pub grace_period: i64, // seconds after the deadline before the box can be swept; opens then need an earlier `CommitFinish`
+    pub dispute_window: i64, // seconds between initiating and finalizing a sweep, at least `MIN_DISPUTE_WINDOW`
+    pub attestor: Pubkey, // signs appeals of pending sweeps, default key = no attestor
+    pub max_relayer_fee_bps: u16, // cap on the fee a relayer may take from the payout of an opened box
+}
+
+impl BoxTerms {
+    // beneficiary + keeper bounty + grace period + dispute window + attestor + relayer fee cap
+    pub const LEN: usize = 32 + 2 + 8 + 8 + 32 + 2;
+
+    /// Fixed terms for boxes created before boxes carried terms, matching what those boxes
+    /// were created under: forfeits go to the protocol, with no keeper bounty, grace period,
+    /// attestor or relayer fee. The dispute window is the minimum every sweep now needs.
+    pub fn legacy(treasury: Pubkey) -> Self {
+        Self {
+            beneficiary: treasury,
+            keeper_bounty_bps: 0,
+            grace_period: 0,
+            dispute_window: MIN_DISPUTE_WINDOW,
+            attestor: Pubkey::default(),
+            max_relayer_fee_bps: 0,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.keeper_bounty_bps <= MAX_KEEPER_BOUNTY_BPS
+            && self.grace_period >= 0
+            && self.dispute_window >= MIN_DISPUTE_WINDOW
+            && self.max_relayer_fee_bps <= MAX_RELAYER_FEE_BPS
+    }
+
+    /// Earliest time a box with the given (effective) deadline can be swept,
+    /// in the units of the box's deadline kind
+    pub fn sweepable_at(&self, deadline: i64, kind: DeadlineKind) -> i64 {
+        deadline.saturating_add(kind.span(self.grace_period))
+    }
+
+    /// Earliest time a sweep initiated at `initiated_at` can be finalized
+    pub fn finalizable_at(&self, initiated_at: i64) -> i64 {
+        initiated_at.saturating_add(self.dispute_window)
+    }
+
+    /// Keeper's share of a forfeited `amount`
+    pub fn keeper_bounty(&self, amount: u64) -> u64 {
+        (amount as u128 * self.keeper_bounty_bps as u128 / 10_000) as u64
+    }
+
+    /// Largest fee a relayer may take when opening a box holding `amount`
+    pub fn max_relayer_fee(&self, amount: u64) -> u64 {
+        (amount as u128 * self.max_relayer_fee_bps as u128 / 10_000) as u64
+    }
+}
+
+/// Settings that can only change through `QueueConfig`/`ExecuteConfig`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct ProtocolConfig {
+    pub authority: Pubkey,
+    pub sol_limits: StakeLimits, // lamports
+    pub signers: [Pubkey; MAX_SIGNERS], // first `signer_count` entries are used
+    pub signer_count: u8,
+    pub threshold: u8, // 0 = `authority` alone approves
+    pub terms: BoxTerms,
+    pub referral_share_bps: u16, // share of forfeits paid to the treasury that goes to the sender's referrer
+}
+
+impl ProtocolConfig {
+    // authority + SOL limits + signer set + count + threshold + terms + referral share
+    pub const LEN: usize = 32 + StakeLimits::LEN + 32 * MAX_SIGNERS + 1 + 1 + BoxTerms::LEN + 2;
+
+    pub fn new(authority: Pubkey, beneficiary: Pubkey) -> Self {
+        Self {
+            authority,
+            sol_limits: StakeLimits::default(),
+            signers: [Pubkey::default(); MAX_SIGNERS],
+            signer_count: 0,
+            threshold: 0,
+            terms: BoxTerms {
+                beneficiary,
+                keeper_bounty_bps: 0,
+                grace_period: 0,
+                dispute_window: MIN_DISPUTE_WINDOW,
+                attestor: Pubkey::default(),
+                max_relayer_fee_bps: DEFAULT_RELAYER_FEE_BPS,
+            },
+            referral_share_bps: 0,
+        }
+    }
+
+    /// Part of a forfeit of `amount` paid to the treasury that goes to the referrer.
+    /// Forfeits are the protocol's only income, since opened boxes pay no fee, so the share is
+    /// a cut of that income: a referrer earns when the readers they brought in miss their
+    /// deadline, never when they finish. This is a deliberate product choice; paying referrers
+    /// for completions would need a fee on opened boxes first.
+    pub fn referral_share(&self, amount: u64) -> u64 {
+        (amount as u128 * self.referral_share_bps as u128 / 10_000) as u64
+    }
+
+    pub fn signer_set(&self) -> &[Pubkey] {
+        &self.signers[..self.signer_count as usize]
+    }
+
+    pub fn validate(&self) -> Result<(), ProgramError> {
+        if !self.sol_limits.is_valid() || !self.terms.is_valid() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if self.referral_share_bps > 10_000 {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let count = self.signer_count as usize;
+        if count > MAX_SIGNERS
+            || self.threshold as usize > count
+            || (self.threshold == 0) != (count == 0)
+        {
+            return Err(EscrowError::InvalidSignerSet.into());
+        }
+
+        let signer_set = self.signer_set();
+        for (i, signer) in signer_set.iter().enumerate() {
+            if signer_set[..i].contains(signer) {
+                return Err(EscrowError::InvalidSignerSet.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// First byte of every program-owned account, so that an account of one kind is never read
+/// as another kind that happens to have the same size
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum AccountKind {
+    ProgramState = 1,
+    Box = 2,
+    TokenBox = 3,
+    UserProfile = 4,
+    Referral = 5,
+    MintConfig = 6,
+    BoxMeta = 7,
+    PendingConfig = 8,
+}
+
+/// Decodes an account written with `kind` and `version` as its first two bytes. Accounts of
+/// `legacy_len` bytes predate both and must go through `MigrateAccount` first.
+fn unpack_account<T: BorshDeserialize>(
+    data: &[u8],
+    kind: AccountKind,
+    version: u8,
+    legacy_len: Option<usize>,
+) -> Result<T, ProgramError> {
+    if legacy_len == Some(data.len()) {
+        return Err(EscrowError::AccountNotMigrated.into());
+    }
+
+    if data.first() != Some(&(kind as u8)) || data.get(1) != Some(&version) {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    T::try_from_slice(data).map_err(|_| ProgramError::InvalidAccountData)
+}
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct ProgramState {
-    pub authority: Pubkey,
+    pub kind: AccountKind, // `AccountKind::ProgramState`
+    pub version: u8, // `ProgramState::VERSION`
+    pub config: ProtocolConfig,
+    pub active_boxes: u64,
+    pub active_token_boxes: u64,
+    pub total_locked_lamports: u64,
+    pub total_opened: u64,
+    pub total_swept: u64,
+    pub total_boxes_created: u64,
+    pub paused_create: bool,
+    pub paused_open: bool,
+    pub paused_sweep: bool,
+    pub open_paused_at: i64,    // unix time OpenBox was paused, 0 while running
+    pub open_paused_total: i64, // seconds OpenBox spent paused before `open_paused_at`
 }
 
 impl ProgramState {
-    pub const LEN: usize = 32; // authority pubkey
+    pub const LEN: usize = 1 + 1 + ProtocolConfig::LEN + 8 * 6 + 3 + 8 + 8; // kind + version + config + counters + pause flags + pause clock
+    pub const VERSION: u8 = 1;
+
+    pub fn new(authority: Pubkey, treasury: Pubkey) -> Self {
+        Self {
+            kind: AccountKind::ProgramState,
+            version: Self::VERSION,
+            config: ProtocolConfig::new(authority, treasury),
+            active_boxes: 0,
+            active_token_boxes: 0,
+            total_locked_lamports: 0,
+            total_opened: 0,
+            total_swept: 0,
+            total_boxes_created: 0,
+            paused_create: false,
+            paused_open: false,
+            paused_sweep: false,
+            open_paused_at: 0,
+            open_paused_total: 0,
+        }
+    }
+
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        unpack_account(data, AccountKind::ProgramState, Self::VERSION, Some(LegacyProgramState::LEN))
+    }
+
+    /// Total seconds OpenBox has been paused up to `now`, including a pause still in progress
+    pub fn open_pause_elapsed(&self, now: i64) -> i64 {
+        if self.paused_open {
+            self.open_paused_total.saturating_add(now.saturating_sub(self.open_paused_at))
+        } else {
+            self.open_paused_total
+        }
+    }
+
+    pub fn record_box_created(&mut self, lamports: u64) -> Result<(), ProgramError> {
+        self.active_boxes = self.active_boxes.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.total_locked_lamports = self.total_locked_lamports.checked_add(lamports).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.total_boxes_created = self.total_boxes_created.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    pub fn record_token_box_created(&mut self) -> Result<(), ProgramError> {
+        self.active_token_boxes = self.active_token_boxes.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.total_boxes_created = self.total_boxes_created.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    pub fn record_box_opened(&mut self, lamports: u64) -> Result<(), ProgramError> {
+        self.release_box(lamports)?;
+        self.total_opened = self.total_opened.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    pub fn record_token_box_opened(&mut self) -> Result<(), ProgramError> {
+        self.release_token_box()?;
+        self.total_opened = self.total_opened.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    pub fn record_box_swept(&mut self, lamports: u64) -> Result<(), ProgramError> {
+        self.release_box(lamports)?;
+        self.total_swept = self.total_swept.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    pub fn record_token_box_swept(&mut self) -> Result<(), ProgramError> {
+        self.release_token_box()?;
+        self.total_swept = self.total_swept.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    fn release_box(&mut self, lamports: u64) -> Result<(), ProgramError> {
+        self.active_boxes = self.active_boxes.checked_sub(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.total_locked_lamports = self.total_locked_lamports.checked_sub(lamports).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    fn release_token_box(&mut self) -> Result<(), ProgramError> {
+        self.active_token_boxes = self.active_token_boxes.checked_sub(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct Box {
+    pub kind: AccountKind, // `AccountKind::Box`
+    pub version: u8, // `Box::VERSION`
     pub sender: Pubkey,
     pub id: Pubkey,
     pub deadline: i64,
     pub amount: u64,
+    pub pause_offset: i64, // `ProgramState::open_pause_elapsed` at creation
+    pub terms: BoxTerms,
+    pub arbiter: Pubkey, // default key = no arbiter
+    pub sweep_initiated_at: i64, // 0 = no sweep pending
+    pub rotated_id: Pubkey, // replaces `id` as the box key once set, default key = not rotated
+    pub guardians: GuardianSet,
+    pub recovery: Recovery,
+    pub book: BookRef,
+    pub reading_mode: ReadingMode,
+    pub deadline_kind: DeadlineKind, // unit of `deadline`
+    pub finish: FinishCommitment,
+    pub timer: ReadingTimer,
 }
 
 impl Box {
-    pub const LEN: usize = 32 + 32 + 8 + 8; // sender + id + deadline + amount
+    // kind + version + sender + id + deadline + amount + pause offset + terms + arbiter
+    // + sweep initiated at + rotated id + guardians + recovery + book + reading mode
+    // + deadline kind + finish + timer
+    pub const LEN: usize = 1 + 1 + 32 + 32 + 8 + 8 + 8 + BoxTerms::LEN + 32
+        + 8 + 32 + GuardianSet::LEN + Recovery::LEN + BookRef::LEN + ReadingMode::LEN
+        + DeadlineKind::LEN + FinishCommitment::LEN + ReadingTimer::LEN;
+    pub const VERSION: u8 = 1;
+
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        unpack_account(data, AccountKind::Box, Self::VERSION, Some(LegacyBox::LEN))
+    }
+
+    /// A box created before boxes carried terms, settled under `BoxTerms::legacy` from now on
+    pub fn from_legacy(legacy: LegacyBox, treasury: Pubkey) -> Self {
+        Self {
+            kind: AccountKind::Box,
+            version: Self::VERSION,
+            sender: legacy.sender,
+            id: legacy.id,
+            deadline: legacy.deadline,
+            amount: legacy.amount,
+            pause_offset: 0,
+            terms: BoxTerms::legacy(treasury),
+            arbiter: Pubkey::default(),
+            sweep_initiated_at: 0,
+            rotated_id: Pubkey::default(),
+            guardians: GuardianSet::default(),
+            recovery: Recovery::default(),
+            book: BookRef::default(),
+            reading_mode: ReadingMode::Unspecified,
+            deadline_kind: DeadlineKind::UnixTimestamp,
+            finish: FinishCommitment::default(),
+            timer: ReadingTimer::default(),
+        }
+    }
+
+    /// Key that currently controls the box; `id` stays part of the PDA seeds
+    pub fn id_key(&self, now: i64) -> Pubkey {
+        if self.recovery.is_effective(now) {
+            self.recovery.new_id
+        } else if self.rotated_id == Pubkey::default() {
+            self.id
+        } else {
+            self.rotated_id
+        }
+    }
+
+    /// Address payouts are redirected to by a guardian recovery that took effect
+    pub fn recovered_payout(&self, now: i64) -> Option<Pubkey> {
+        self.recovery.is_effective(now).then_some(self.recovery.payout)
+    }
+
+    /// Current time in the units of the box's deadline
+    pub fn now(&self, clock: &Clock) -> i64 {
+        self.deadline_kind.now(clock)
+    }
+
+    /// Deadline pushed out by the time OpenBox was paused during the box's lifetime
+    pub fn effective_deadline(&self, program_state: &ProgramState, clock: &Clock) -> i64 {
+        let paused = program_state
+            .open_pause_elapsed(clock.unix_timestamp)
+            .saturating_sub(self.pause_offset);
+        self.deadline.saturating_add(self.deadline_kind.span(paused))
+    }
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct TokenBox {
+    pub kind: AccountKind, // `AccountKind::TokenBox`
+    pub version: u8, // `TokenBox::VERSION`
     pub sender: Pubkey,
     pub id: Pubkey,
     pub deadline: i64,
     pub amount: u64,
     pub mint: Pubkey,
-}impl TokenBox {
+    pub pause_offset: i64, // `ProgramState::open_pause_elapsed` at creation
+    pub terms: BoxTerms,
+    pub arbiter: Pubkey, // default key = no arbiter
+    pub sweep_initiated_at: i64, // 0 = no sweep pending
+    pub rotated_id: Pubkey, // replaces `id` as the box key once set, default key = not rotated
+    pub guardians: GuardianSet,
+    pub recovery: Recovery,
+    pub book: BookRef,
+    pub reading_mode: ReadingMode,
+    pub deadline_kind: DeadlineKind, // unit of `deadline`
+    pub finish: FinishCommitment,
+    pub timer: ReadingTimer,
+}
+
+impl TokenBox {
+    // kind + version + sender + id + deadline + amount + mint + pause offset + terms + arbiter
+    // + sweep initiated at + rotated id + guardians + recovery + book + reading mode
+    // + deadline kind + finish + timer
+    pub const LEN: usize = 1 + 1 + 32 + 32 + 8 + 8 + 32 + 8 + BoxTerms::LEN + 32
+        + 8 + 32 + GuardianSet::LEN + Recovery::LEN + BookRef::LEN + ReadingMode::LEN
+        + DeadlineKind::LEN + FinishCommitment::LEN + ReadingTimer::LEN;
+    pub const VERSION: u8 = 1;
+
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        unpack_account(data, AccountKind::TokenBox, Self::VERSION, Some(LegacyTokenBox::LEN))
+    }
+
+    /// A token box created before boxes carried terms, settled under `BoxTerms::legacy` from now on
+    pub fn from_legacy(legacy: LegacyTokenBox, treasury: Pubkey) -> Self {
+        Self {
+            kind: AccountKind::TokenBox,
+            version: Self::VERSION,
+            sender: legacy.sender,
+            id: legacy.id,
+            deadline: legacy.deadline,
+            amount: legacy.amount,
+            mint: legacy.mint,
+            pause_offset: 0,
+            terms: BoxTerms::legacy(treasury),
+            arbiter: Pubkey::default(),
+            sweep_initiated_at: 0,
+            rotated_id: Pubkey::default(),
+            guardians: GuardianSet::default(),
+            recovery: Recovery::default(),
+            book: BookRef::default(),
+            reading_mode: ReadingMode::Unspecified,
+            deadline_kind: DeadlineKind::UnixTimestamp,
+            finish: FinishCommitment::default(),
+            timer: ReadingTimer::default(),
+        }
+    }
+
+    /// Key that currently controls the box; `id` stays part of the PDA seeds
+    pub fn id_key(&self, now: i64) -> Pubkey {
+        if self.recovery.is_effective(now) {
+            self.recovery.new_id
+        } else if self.rotated_id == Pubkey::default() {
+            self.id
+        } else {
+            self.rotated_id
+        }
+    }
+
+    /// Address payouts are redirected to by a guardian recovery that took effect
+    pub fn recovered_payout(&self, now: i64) -> Option<Pubkey> {
+        self.recovery.is_effective(now).then_some(self.recovery.payout)
+    }
+
+    /// Current time in the units of the box's deadline
+    pub fn now(&self, clock: &Clock) -> i64 {
+        self.deadline_kind.now(clock)
+    }
+
+    /// Deadline pushed out by the time OpenBox was paused during the box's lifetime
+    pub fn effective_deadline(&self, program_state: &ProgramState, clock: &Clock) -> i64 {
+        let paused = program_state
+            .open_pause_elapsed(clock.unix_timestamp)
+            .saturating_sub(self.pause_offset);
+        self.deadline.saturating_add(self.deadline_kind.span(paused))
+    }
+}
+
+/// Per-user reading record, seeds: ["profile", sender]. Box counts and streaks cover SOL and
+/// token boxes alike; the staked and lost totals are in lamports, so they cover SOL boxes only
+/// and a reader staking tokens shows no totals.
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct UserProfile {
+    pub kind: AccountKind, // `AccountKind::UserProfile`
+    pub version: u8, // `UserProfile::VERSION`
+    pub owner: Pubkey,
+    pub boxes_created: u32,
+    pub boxes_completed: u32,
+    pub boxes_forfeited: u32,
+    pub total_staked: u64, // lamports staked in SOL boxes; token stakes are not counted
+    pub total_lost: u64,   // lamports forfeited from SOL boxes; token forfeits are not counted
+    pub current_streak: u32,
+    pub best_streak: u32,
+    pub referrer: Pubkey, // default key = not referred
+}
+
+impl UserProfile {
+    // kind + version + owner + created + completed + forfeited + staked + lost + streak + best streak
+    // + referrer
+    pub const LEN: usize = 1 + 1 + 32 + 4 + 4 + 4 + 8 + 8 + 4 + 4 + 32;
+    pub const VERSION: u8 = 1;
+
+    pub fn new(owner: Pubkey) -> Self {
+        Self {
+            kind: AccountKind::UserProfile,
+            version: Self::VERSION,
+            owner,
+            boxes_created: 0,
+            boxes_completed: 0,
+            boxes_forfeited: 0,
+            total_staked: 0,
+            total_lost: 0,
+            current_streak: 0,
+            best_streak: 0,
+            referrer: Pubkey::default(),
+        }
+    }
+
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        unpack_account(data, AccountKind::UserProfile, Self::VERSION, None)
+    }
+
+    /// `lamports` is the SOL stake, zero for token boxes
+    pub fn record_created(&mut self, lamports: u64) -> Result<(), ProgramError> {
+        self.boxes_created = self.boxes_created.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.total_staked = self.total_staked.checked_add(lamports).ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    pub fn record_completed(&mut self) -> Result<(), ProgramError> {
+        self.boxes_completed = self.boxes_completed.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.current_streak = self.current_streak.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.best_streak = self.best_streak.max(self.current_streak);
+        Ok(())
+    }
+
+    /// `lamports` is the forfeited SOL stake, zero for token boxes
+    pub fn record_forfeited(&mut self, lamports: u64) -> Result<(), ProgramError> {
+        self.boxes_forfeited = self.boxes_forfeited.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.total_lost = self.total_lost.checked_add(lamports).ok_or(ProgramError::ArithmeticOverflow)?;
+        self.current_streak = 0;
+        Ok(())
+    }
+}
+
+/// `ProgramState` as first deployed, before accounts carried a kind and version byte
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct LegacyProgramState {
+    pub authority: Pubkey,
+}
+
+impl LegacyProgramState {
+    pub const LEN: usize = 32; // authority
+}
+
+/// `Box` as first deployed, before accounts carried a kind and version byte
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct LegacyBox {
+    pub sender: Pubkey,
+    pub id: Pubkey,
+    pub deadline: i64,
+    pub amount: u64,
+}
+
+impl LegacyBox {
+    pub const LEN: usize = 32 + 32 + 8 + 8; // sender + id + deadline + amount
+}
+
+/// `TokenBox` as first deployed, before accounts carried a kind and version byte
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct LegacyTokenBox {
+    pub sender: Pubkey,
+    pub id: Pubkey,
+    pub deadline: i64,
+    pub amount: u64,
+    pub mint: Pubkey,
+}
+
+impl LegacyTokenBox {
     pub const LEN: usize = 32 + 32 + 8 + 8 + 32; // sender + id + deadline + amount + mint
-}
\ No newline at end of file
+}
+
+/// SOL rewards of a referrer, seeds: ["referral", referrer]. Opened by the referrer's first
+/// `ClaimReferralRewards`; accrued rewards are the lamports held above the rent-exempt minimum.
+/// Token rewards are paid straight to the referrer's ATA of the box's mint.
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct Referral {
+    pub kind: AccountKind, // `AccountKind::Referral`
+    pub version: u8, // `Referral::VERSION`
+    pub referrer: Pubkey,
+    pub total_earned: u64,  // lamports
+    pub total_claimed: u64, // lamports
+}
+
+impl Referral {
+    pub const LEN: usize = 1 + 1 + 32 + 8 + 8; // kind + version + referrer + earned + claimed
+    pub const VERSION: u8 = 1;
+
+    pub fn new(referrer: Pubkey) -> Self {
+        Self {
+            kind: AccountKind::Referral,
+            version: Self::VERSION,
+            referrer,
+            total_earned: 0,
+            total_claimed: 0,
+        }
+    }
+
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        unpack_account(data, AccountKind::Referral, Self::VERSION, None)
+    }
+}
+
+/// Allowlist entry for token boxes of one mint, seeds: ["mint_config", mint]
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct MintConfig {
+    pub kind: AccountKind, // `AccountKind::MintConfig`
+    pub version: u8, // `MintConfig::VERSION`
+    pub mint: Pubkey,
+    pub decimals: u8,
+    pub limits: StakeLimits, // raw token units
+    pub allow_freeze_authority: bool,
+}
+
+impl MintConfig {
+    pub const LEN: usize = 1 + 1 + 32 + 1 + StakeLimits::LEN + 1; // kind + version + mint + decimals + limits + freeze flag
+    pub const VERSION: u8 = 1;
+
+    pub fn new(mint: Pubkey, decimals: u8, limits: StakeLimits, allow_freeze_authority: bool) -> Self {
+        Self {
+            kind: AccountKind::MintConfig,
+            version: Self::VERSION,
+            mint,
+            decimals,
+            limits,
+            allow_freeze_authority,
+        }
+    }
+
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        unpack_account(data, AccountKind::MintConfig, Self::VERSION, None)
+    }
+}
+
+/// Token metadata of completion badges
+pub const BADGE_SYMBOL: &str = "READ";
+pub const BADGE_DEFAULT_NAME: &str = "Reading badge";
+
+/// Maximum byte lengths of the `BoxMetaFields` strings
+pub const MAX_META_TITLE_LEN: usize = 128;
+pub const MAX_META_AUTHOR_LEN: usize = 64;
+pub const MAX_META_NOTE_LEN: usize = 280;
+pub const MAX_META_LANGUAGE_LEN: usize = 16;
+
+/// Free-form labels of a box; empty strings mean unset
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default, PartialEq)]
+pub struct BoxMetaFields {
+    pub title: String,
+    pub author: String,
+    pub note: String,
+    pub language: String, // BCP 47 tag, e.g. "en" or "pt-BR"
+}
+
+impl BoxMetaFields {
+    pub fn validate(&self) -> Result<(), ProgramError> {
+        if self.title.len() > MAX_META_TITLE_LEN
+            || self.author.len() > MAX_META_AUTHOR_LEN
+            || self.note.len() > MAX_META_NOTE_LEN
+            || self.language.len() > MAX_META_LANGUAGE_LEN
+        {
+            return Err(EscrowError::MetaTooLong.into());
+        }
+        Ok(())
+    }
+}
+
+/// Labels of a SOL or token box, seeds: ["box_meta", box_pda].
+/// Sized to its contents, so it is resized whenever the labels change.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct BoxMeta {
+    pub kind: AccountKind, // `AccountKind::BoxMeta`
+    pub version: u8, // `BoxMeta::VERSION`
+    pub box_pda: Pubkey,
+    pub sender: Pubkey, // pays the rent and gets it back on close
+    pub fields: BoxMetaFields,
+}
+
+impl BoxMeta {
+    pub const VERSION: u8 = 1;
+
+    pub fn new(box_pda: Pubkey, sender: Pubkey, fields: BoxMetaFields) -> Self {
+        Self {
+            kind: AccountKind::BoxMeta,
+            version: Self::VERSION,
+            box_pda,
+            sender,
+            fields,
+        }
+    }
+
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        unpack_account(data, AccountKind::BoxMeta, Self::VERSION, None)
+    }
+
+    pub fn space(&self) -> usize {
+        // kind + version + box + sender + string prefixes + strings
+        1 + 1 + 32 + 32 + 4 * 4
+            + self.fields.title.len()
+            + self.fields.author.len()
+            + self.fields.note.len()
+            + self.fields.language.len()
+    }
+}
+
+/// Config change waiting for its timelock, seeds: ["pending_config"]
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct PendingConfig {
+    pub kind: AccountKind, // `AccountKind::PendingConfig`
+    pub version: u8, // `PendingConfig::VERSION`
+    pub config: ProtocolConfig,
+    pub execute_after: i64,
+    pub payer: Pubkey, // refunded the account rent on execute/cancel
+}
+
+impl PendingConfig {
+    pub const LEN: usize = 1 + 1 + ProtocolConfig::LEN + 8 + 32; // kind + version + config + execute after + payer
+    pub const VERSION: u8 = 1;
+
+    pub fn new(config: ProtocolConfig, execute_after: i64, payer: Pubkey) -> Self {
+        Self {
+            kind: AccountKind::PendingConfig,
+            version: Self::VERSION,
+            config,
+            execute_after,
+            payer,
+        }
+    }
+
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        unpack_account(data, AccountKind::PendingConfig, Self::VERSION, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms() -> BoxTerms {
+        ProtocolConfig::new(Pubkey::new_unique(), Pubkey::new_unique()).terms
+    }
+
+    #[test]
+    fn legacy_layouts_need_migration() {
+        let not_migrated = Err(EscrowError::AccountNotMigrated.into());
+        assert_eq!(ProgramState::unpack(&[7; LegacyProgramState::LEN]).map(|_| ()), not_migrated);
+        assert_eq!(Box::unpack(&[7; LegacyBox::LEN]).map(|_| ()), not_migrated);
+        assert_eq!(TokenBox::unpack(&[7; LegacyTokenBox::LEN]).map(|_| ()), not_migrated);
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut data = borsh::to_vec(&UserProfile::new(Pubkey::new_unique())).unwrap();
+        assert_eq!(data.len(), UserProfile::LEN);
+        assert!(UserProfile::unpack(&data).is_ok());
+
+        data[1] = UserProfile::VERSION + 1;
+        assert_eq!(UserProfile::unpack(&data).map(|_| ()), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn account_of_another_kind_is_rejected() {
+        let meta = BoxMeta::new(Pubkey::new_unique(), Pubkey::new_unique(), BoxMetaFields::default());
+        let mut data = borsh::to_vec(&meta).unwrap();
+        data.resize(Box::LEN, 0);
+        assert_eq!(Box::unpack(&data).map(|_| ()), Err(ProgramError::InvalidAccountData));
+
+        data.resize(TokenBox::LEN, 0);
+        assert_eq!(TokenBox::unpack(&data).map(|_| ()), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn later_layouts_are_versioned() {
+        let config = ProtocolConfig::new(Pubkey::new_unique(), Pubkey::new_unique());
+        let meta = BoxMeta::new(Pubkey::new_unique(), Pubkey::new_unique(), BoxMetaFields {
+            title: "Dune".to_string(),
+            ..BoxMetaFields::default()
+        });
+
+        let layouts = [
+            (borsh::to_vec(&Referral::new(Pubkey::new_unique())).unwrap(), Referral::LEN),
+            (borsh::to_vec(&MintConfig::new(Pubkey::new_unique(), 6, StakeLimits::default(), false)).unwrap(), MintConfig::LEN),
+            (borsh::to_vec(&PendingConfig::new(config, 0, Pubkey::new_unique())).unwrap(), PendingConfig::LEN),
+            (borsh::to_vec(&meta).unwrap(), meta.space()),
+        ];
+
+        for (mut data, len) in layouts {
+            assert_eq!(data.len(), len);
+            assert_eq!(data[1], 1);
+            data[1] = 2;
+            assert!(Referral::unpack(&data).is_err());
+            assert!(MintConfig::unpack(&data).is_err());
+            assert!(PendingConfig::unpack(&data).is_err());
+            assert!(BoxMeta::unpack(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn migrated_box_keeps_stake_and_deadline() {
+        let legacy = LegacyBox {
+            sender: Pubkey::new_unique(),
+            id: Pubkey::new_unique(),
+            deadline: 1_700_000_000,
+            amount: 250_000_000,
+        };
+        let data = borsh::to_vec(&legacy).unwrap();
+        assert_eq!(data.len(), LegacyBox::LEN);
+
+        let treasury = Pubkey::new_unique();
+        let migrated = Box::from_legacy(LegacyBox::try_from_slice(&data).unwrap(), treasury);
+        let data = borsh::to_vec(&migrated).unwrap();
+        assert_eq!(data.len(), Box::LEN);
+
+        let escrow_box = Box::unpack(&data).unwrap();
+        assert_eq!(escrow_box.sender, legacy.sender);
+        assert_eq!(escrow_box.id_key(0), legacy.id);
+        assert_eq!(escrow_box.deadline, legacy.deadline);
+        assert_eq!(escrow_box.amount, legacy.amount);
+        assert_eq!(escrow_box.terms, BoxTerms::legacy(treasury));
+        assert!(escrow_box.terms.is_valid());
+        assert_eq!(escrow_box.deadline_kind, DeadlineKind::UnixTimestamp);
+    }
+
+    #[test]
+    fn migrated_token_box_keeps_mint() {
+        let legacy = LegacyTokenBox {
+            sender: Pubkey::new_unique(),
+            id: Pubkey::new_unique(),
+            deadline: 1_700_000_000,
+            amount: 42,
+            mint: Pubkey::new_unique(),
+        };
+        assert_eq!(borsh::to_vec(&legacy).unwrap().len(), LegacyTokenBox::LEN);
+
+        let mint = legacy.mint;
+        let data = borsh::to_vec(&TokenBox::from_legacy(legacy, Pubkey::new_unique())).unwrap();
+        assert_eq!(data.len(), TokenBox::LEN);
+        assert_eq!(TokenBox::unpack(&data).unwrap().mint, mint);
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(ProtocolConfig::new(Pubkey::new_unique(), Pubkey::new_unique()).validate(), Ok(()));
+    }
+
+    #[test]
+    fn default_terms_leave_room_for_a_relayer_fee() {
+        let terms = terms();
+        assert_eq!(terms.max_relayer_fee(1_000_000_000), 10_000_000);
+        assert_eq!(terms.max_relayer_fee(0), 0);
+    }
+
+    #[test]
+    fn dispute_window_has_a_minimum() {
+        let mut config = ProtocolConfig::new(Pubkey::new_unique(), Pubkey::new_unique());
+        config.terms.dispute_window = MIN_DISPUTE_WINDOW - 1;
+        assert_eq!(config.validate(), Err(ProgramError::InvalidArgument));
+
+        config.terms.dispute_window = 0;
+        assert_eq!(config.validate(), Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn program_state_len_matches_layout() {
+        let program_state = ProgramState::new(Pubkey::new_unique(), Pubkey::new_unique());
+        let data = borsh::to_vec(&program_state).unwrap();
+        assert_eq!(data.len(), ProgramState::LEN);
+        assert!(ProgramState::unpack(&data).is_ok());
+    }
+
+    const EPOCH: i64 = DeadlineKind::SECONDS_PER_EPOCH;
+
+    /// Clock `into_epoch` seconds after the start of epoch 500
+    fn clock(into_epoch: i64) -> Clock {
+        Clock {
+            slot: 1_000_000,
+            epoch_start_timestamp: 1_700_000_000,
+            epoch: 500,
+            leader_schedule_epoch: 501,
+            unix_timestamp: 1_700_000_000 + into_epoch,
+        }
+    }
+
+    #[test]
+    fn spans_round_up_to_whole_units() {
+        assert_eq!(DeadlineKind::UnixTimestamp.span(86_400), 86_400);
+
+        assert_eq!(DeadlineKind::Slot.span(1), 3);
+        assert_eq!(DeadlineKind::Slot.span(2), 5);
+        assert_eq!(DeadlineKind::Slot.span(86_400), 216_000);
+
+        assert_eq!(DeadlineKind::Epoch.span(0), 0);
+        assert_eq!(DeadlineKind::Epoch.span(1), 1);
+        assert_eq!(DeadlineKind::Epoch.span(86_400), 1);
+        assert_eq!(DeadlineKind::Epoch.span(EPOCH), 1);
+        assert_eq!(DeadlineKind::Epoch.span(EPOCH + 1), 2);
+    }
+
+    #[test]
+    fn deadlines_are_the_requested_days_away() {
+        let clock = clock(1_000);
+        assert_eq!(DeadlineKind::UnixTimestamp.deadline_after(&clock, 1), clock.unix_timestamp + 86_400);
+        assert_eq!(DeadlineKind::Slot.deadline_after(&clock, 1), 1_000_000 + 216_000);
+        assert_eq!(DeadlineKind::Epoch.deadline_after(&clock, 1), 501);
+        assert_eq!(DeadlineKind::Epoch.deadline_after(&clock, 2), 502);
+    }
+
+    #[test]
+    fn epoch_deadlines_count_from_the_epoch_start() {
+        // Epoch 501 starts an hour from now, well short of a day
+        let late = clock(EPOCH - 3_600);
+        assert_eq!(DeadlineKind::Epoch.deadline_after(&late, 1), 502);
+        assert_eq!(DeadlineKind::Epoch.deadline_after(&late, 2), 502);
+
+        let start = clock(0);
+        assert_eq!(DeadlineKind::Epoch.deadline_after(&start, 2), 501);
+        assert_eq!(DeadlineKind::Epoch.deadline_after(&start, 3), 502);
+    }
+
+    #[test]
+    fn unix_estimates_match_deadlines() {
+        let clock = clock(1_000);
+        let now = clock.unix_timestamp;
+
+        let unix = DeadlineKind::UnixTimestamp;
+        assert_eq!(unix.to_unix_estimate(now + 500, &clock), now + 500);
+        assert_eq!(unix.from_unix_estimate(now + 500, &clock), now + 500);
+
+        let slot = DeadlineKind::Slot;
+        assert_eq!(slot.to_unix_estimate(1_000_000 + 5, &clock), now + 2);
+        assert_eq!(slot.from_unix_estimate(now + 2, &clock), 1_000_000 + 5);
+        // Rounding to whole slots never lands before the requested time
+        let requested = now + 1;
+        assert!(slot.to_unix_estimate(slot.from_unix_estimate(requested, &clock), &clock) >= requested);
+
+        let epoch = DeadlineKind::Epoch;
+        let epoch_start = clock.epoch_start_timestamp;
+        assert_eq!(epoch.to_unix_estimate(500, &clock), epoch_start);
+        assert_eq!(epoch.to_unix_estimate(502, &clock), epoch_start + 2 * EPOCH);
+        assert_eq!(epoch.from_unix_estimate(epoch_start + 2 * EPOCH, &clock), 502);
+        assert_eq!(epoch.from_unix_estimate(epoch_start + 2 * EPOCH + 1, &clock), 503);
+    }
+}
@@ -0,0 +1,55 @@
+//! Syscall stubs for the unit tests. `set_syscall_stubs` swaps the stubs of the whole test
+//! process while tests run on parallel threads, so every test installs this same stub and
+//! keeps its own state in thread locals.
+
+use std::cell::RefCell;
+
+use solana_program::{
+    clock::Clock,
+    entrypoint::SUCCESS,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    rent::Rent,
+};
+
+thread_local! {
+    static LOGGED: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    static CLOCK: RefCell<Clock> = RefCell::new(Clock::default());
+}
+
+/// Collects `sol_log_data` output and serves the calling thread's clock and the default rent.
+/// Cross-program invocations are left to the default stub, which does nothing.
+struct TestStubs;
+
+impl SyscallStubs for TestStubs {
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        LOGGED.with(|logged| logged.borrow_mut().extend(fields.iter().map(|field| field.to_vec())));
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let clock = CLOCK.with(|clock| clock.borrow().clone());
+        // The sysvar getters pass a pointer to a `Clock`
+        unsafe { std::ptr::write(var_addr as *mut Clock, clock) };
+        SUCCESS
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        // The sysvar getters pass a pointer to a `Rent`
+        unsafe { std::ptr::write(var_addr as *mut Rent, Rent::default()) };
+        SUCCESS
+    }
+}
+
+/// Installs the stubs with `Clock::get` returning `clock` on the calling thread
+pub fn install(clock: Clock) {
+    set_clock(clock);
+    set_syscall_stubs(Box::new(TestStubs));
+}
+
+pub fn set_clock(clock: Clock) {
+    CLOCK.with(|current| *current.borrow_mut() = clock);
+}
+
+/// `sol_log_data` output of the calling thread since the last call
+pub fn take_logged() -> Vec<Vec<u8>> {
+    LOGGED.with(|logged| logged.take())
+}
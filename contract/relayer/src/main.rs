@@ -0,0 +1,279 @@
+//! Reference relayer for gasless `OpenBox`.
+//!
+//! A reader without SOL builds an `OpenBox` transaction with the relayer as fee payer and
+//! the relayer account as fee recipient, signs it with the box id key and POSTs it to `/open`
+//! as base64 of the bincode-encoded transaction. The relayer checks that the transaction only
+//! opens escrow boxes and pays it at least `--min-fee`, adds its fee-payer signature and
+//! submits it. The response body is the transaction signature, or the reason it was refused.
+//!
+//! Usage: relayer <keypair.json> [--rpc <url>] [--bind <addr>] [--min-fee <lamports>]
+//!
+//! Transactions built against a durable nonce (see `escrow::nonce`) are accepted too, so
+//! opens pre-signed while offline can be handed over later. Such a transaction names its
+//! relayer as fee payer and fee recipient under the reader's signatures, so only the relayer
+//! it was signed for can submit it.
+//!
+//! Defaults target a local `solana-test-validator`. Only SOL boxes without a badge are relayed.
+//! Requests are served one at a time, so a client gets `IO_TIMEOUT` to send its whole request
+//! and to take the response before it is dropped.
+
+use std::{
+    env,
+    error::Error,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    process,
+    time::{Duration, Instant},
+};
+
+use base64::Engine;
+use escrow::{instruction::EscrowInstruction, nonce};
+use solana_commitment_config::CommitmentConfig;
+use solana_keypair::{read_keypair_file, Keypair};
+use solana_pubkey::Pubkey;
+use solana_rpc_client::rpc_client::RpcClient;
+use solana_signer::Signer;
+use solana_transaction::Transaction;
+
+/// Position of the relayer account in `OpenBox` when no badge is minted
+const RELAYER_ACCOUNT_INDEX: usize = 5;
+
+/// Largest request body accepted, well above the size of a transaction
+const MAX_BODY_LEN: usize = 4096;
+
+/// Longest a client may take to send its whole request, or to accept the response
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Config {
+    keypair_path: String,
+    rpc_url: String,
+    bind: String,
+    min_fee: u64,
+}
+
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut args = env::args().skip(1);
+        let mut config = Config {
+            keypair_path: args.next().ok_or("missing keypair path")?,
+            rpc_url: "http://127.0.0.1:8899".to_string(),
+            bind: "127.0.0.1:8080".to_string(),
+            min_fee: 0,
+        };
+
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or(format!("missing value for {flag}"))?;
+            match flag.as_str() {
+                "--rpc" => config.rpc_url = value,
+                "--bind" => config.bind = value,
+                "--min-fee" => config.min_fee = value.parse().map_err(|_| "invalid --min-fee")?,
+                _ => return Err(format!("unknown flag {flag}")),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+struct Relayer {
+    keypair: Keypair,
+    rpc: RpcClient,
+    min_fee: u64,
+}
+
+impl Relayer {
+    /// Checks, co-signs and submits an open transaction, returning its signature
+    fn relay(&self, body: &str) -> Result<String, Box<dyn Error>> {
+        let data = base64::engine::general_purpose::STANDARD.decode(body.trim())?;
+        let mut transaction: Transaction = bincode::deserialize(&data)?;
+
+        self.check(&transaction)?;
+
+        let recent_blockhash = transaction.message.recent_blockhash;
+        transaction.try_partial_sign(&[&self.keypair], recent_blockhash)?;
+
+        let signature = self.rpc.send_and_confirm_transaction(&transaction)?;
+        Ok(signature.to_string())
+    }
+
+    /// Refuses anything but escrow `OpenBox` instructions that pay this relayer enough,
+    /// optionally preceded by a durable nonce advance, so the relayer key only ever signs
+    /// as fee payer
+    fn check(&self, transaction: &Transaction) -> Result<(), String> {
+        let relayer = self.keypair.pubkey();
+        let message = &transaction.message;
+
+        if message.account_keys.first() != Some(&relayer) {
+            return Err("relayer must be the fee payer".to_string());
+        }
+
+        if message.instructions.is_empty() {
+            return Err("no instructions".to_string());
+        }
+
+        for (position, instruction) in message.instructions.iter().enumerate() {
+            let program_id = message
+                .account_keys
+                .get(instruction.program_id_index as usize)
+                .ok_or("invalid program index")?;
+
+            if position == 0 && nonce::is_advance_nonce(program_id, &instruction.data) {
+                let uses_relayer = instruction
+                    .accounts
+                    .iter()
+                    .any(|index| message.account_keys.get(*index as usize) == Some(&relayer));
+                if uses_relayer {
+                    return Err("relayer key used by the nonce advance".to_string());
+                }
+                continue;
+            }
+
+            if *program_id != escrow::id() {
+                return Err(format!("instruction for unsupported program {program_id}"));
+            }
+
+            match EscrowInstruction::unpack(&instruction.data).map_err(|err| err.to_string())? {
+                EscrowInstruction::OpenBox {
+                    mint_badge: false,
+                    relayer_fee,
+                    ..
+                } if relayer_fee >= self.min_fee.max(1) => {}
+                _ => return Err("only OpenBox without a badge and with a relayer fee is relayed".to_string()),
+            }
+
+            let account = |index: usize| -> Result<&Pubkey, String> {
+                let key_index = *instruction.accounts.get(index).ok_or("missing accounts")?;
+                message
+                    .account_keys
+                    .get(key_index as usize)
+                    .ok_or_else(|| "invalid account index".to_string())
+            };
+
+            if *account(RELAYER_ACCOUNT_INDEX)? != relayer {
+                return Err("relayer fee must be paid to the relayer".to_string());
+            }
+
+            for index in (0..instruction.accounts.len()).filter(|index| *index != RELAYER_ACCOUNT_INDEX) {
+                if *account(index)? == relayer {
+                    return Err("relayer key used as a box account".to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Stream whose reads fail once `deadline` has passed, however slowly the bytes trickle in
+struct DeadlineReader<'s> {
+    stream: &'s TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Reads a `POST /open` request and returns its body
+fn read_request(stream: &TcpStream) -> Result<String, String> {
+    let mut reader = BufReader::new(DeadlineReader {
+        stream,
+        deadline: Instant::now() + IO_TIMEOUT,
+    });
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).map_err(|err| err.to_string())?;
+    if !request_line.starts_with("POST /open ") {
+        return Err("expected POST /open".to_string());
+    }
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|err| err.to_string())?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| "invalid Content-Length")?;
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_LEN {
+        return Err("request too large".to_string());
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).map_err(|err| err.to_string())?;
+    String::from_utf8(body).map_err(|_| "body is not UTF-8".to_string())
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    // The client may already be gone; nothing to do about it
+    let _ = stream.write_all(response.as_bytes());
+}
+
+fn main() {
+    let config = Config::from_args().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        eprintln!("usage: relayer <keypair.json> [--rpc <url>] [--bind <addr>] [--min-fee <lamports>]");
+        process::exit(2);
+    });
+
+    let keypair = read_keypair_file(&config.keypair_path).unwrap_or_else(|err| {
+        eprintln!("cannot read keypair {}: {err}", config.keypair_path);
+        process::exit(1);
+    });
+
+    let relayer = Relayer {
+        keypair,
+        rpc: RpcClient::new_with_commitment(config.rpc_url.clone(), CommitmentConfig::confirmed()),
+        min_fee: config.min_fee,
+    };
+
+    let listener = TcpListener::bind(&config.bind).unwrap_or_else(|err| {
+        eprintln!("cannot bind {}: {err}", config.bind);
+        process::exit(1);
+    });
+
+    println!(
+        "relaying OpenBox for {} on {} via {}",
+        relayer.keypair.pubkey(),
+        config.bind,
+        config.rpc_url
+    );
+
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+
+        if stream.set_write_timeout(Some(IO_TIMEOUT)).is_err() {
+            continue;
+        }
+
+        match read_request(&stream) {
+            Ok(body) => match relayer.relay(&body) {
+                Ok(signature) => write_response(&mut stream, "200 OK", &signature),
+                Err(err) => write_response(&mut stream, "422 Unprocessable Entity", &err.to_string()),
+            },
+            Err(err) => write_response(&mut stream, "400 Bad Request", &err),
+        }
+    }
+}